@@ -1,13 +1,48 @@
+use fsd_interface::messages::PilotPositionUpdateMessage;
+use serde::Serialize;
 
 
-#[allow(unused)]
+
+/// A single target as it was last sent to the ATC client.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Aircraft {
-    callsign: String,
-    uid: u32,
-    lat: f32,
-    lon: f32,
-    alt: f32,
-    hdg: u16,
-    gs: u16,    
+    pub callsign: String,
+    pub uid: u32,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    pub pressure_alt: f64,
+    pub hdg: f64,
+    pub gs: u32,
+    pub transponder: String,
+    pub source: TrafficSource,
+    /// The local controller tracking the aircraft
+    pub owner: Option<String>,
+}
+impl Aircraft {
+    pub fn from_position(uid: u32, position: &PilotPositionUpdateMessage, source: TrafficSource) -> Aircraft {
+        Aircraft {
+            callsign: position.callsign.clone(),
+            uid,
+            lat: position.latitude,
+            lon: position.longitude,
+            alt: position.true_altitude,
+            pressure_alt: position.pressure_altitude,
+            hdg: position.heading,
+            gs: position.ground_speed,
+            transponder: position.transponder_code.to_string(),
+            source,
+            owner: None,
+        }
+    }
 }
 
+/// Where a target's position came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficSource {
+    /// The simulator's TCAS tables
+    Simulator,
+    /// The network data feed, for pilots the simulator hasn't injected
+    Network,
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use fsd_interface::messages::FlightPlanAmendmentMessage;
+use serde::Serialize;
+
+use crate::{network::Network, vatsim::FlightPlan};
+
+
+
+/// A controller's amendment to a flight plan.
+#[derive(Debug, Clone, Serialize)]
+pub struct Amendment {
+    /// The revision of the network's plan which was amended
+    pub base_revision: Option<usize>,
+    /// Counts the local amendments made on top of the same network revision, starting at 1
+    pub revision: usize,
+    pub amended_by: String,
+    /// The network whose plan was amended
+    pub network: Network,
+    #[serde(skip)]
+    pub flight_plan: fsd_interface::FlightPlan,
+}
+
+
+/// Flight plan amendments made by the connected controllers, kept as an overlay on top of the network's
+/// plans. An amendment applies until the network's plan moves on to a new revision, at which point the
+/// pilot's own change wins. It only applies to the plan on the network whose data the controller who made
+/// it was using, as a pilot on another network with the same callsign is a different flight.
+#[derive(Default)]
+pub struct AmendmentOverlay {
+    amendments: HashMap<String, Amendment>,
+}
+impl AmendmentOverlay {
+    /// Records an amendment to a plan on `network` whose revision is currently `base_revision`, returning
+    /// the amendment's local revision.
+    pub fn amend(&mut self, msg: &FlightPlanAmendmentMessage, network: Network, base_revision: Option<usize>) -> usize {
+        let callsign = msg.callsign.to_uppercase();
+        let revision = match self.amendments.get(&callsign) {
+            Some(previous) if previous.network == network && previous.base_revision == base_revision => previous.revision + 1,
+            _ => 1,
+        };
+        self.amendments.insert(callsign, Amendment {
+            base_revision,
+            revision,
+            amended_by: msg.from.clone(),
+            network,
+            flight_plan: msg.flight_plan.clone(),
+        });
+        revision
+    }
+
+    /// The plan to send for an aircraft on `network`: the amended one if there is one which still applies,
+    /// otherwise the network's. Amendments to an older network revision are discarded.
+    pub fn apply(&mut self, callsign: &str, network: Network, network_plan: Option<FlightPlan>) -> Option<fsd_interface::FlightPlan> {
+        let callsign = callsign.to_uppercase();
+        let network_revision = network_plan.as_ref().map(|flight_plan| flight_plan.revision_id);
+        match self.amendments.get(&callsign).filter(|amendment| amendment.network == network) {
+            Some(amendment) if network_plan.is_none() || amendment.base_revision == network_revision => return Some(amendment.flight_plan.clone()),
+            Some(amendment) => {
+                println!("Discarding amendment {} to {}'s flight plan by {}, as the network's plan has changed", amendment.revision, callsign, amendment.amended_by);
+                self.amendments.remove(&callsign);
+            },
+            None => {},
+        }
+        network_plan.map(fsd_interface::FlightPlan::from)
+    }
+
+    pub fn get(&self, callsign: &str, network: Network) -> Option<&Amendment> {
+        self.amendments.get(&callsign.to_uppercase()).filter(|amendment| amendment.network == network)
+    }
+
+    /// Every amendment which hasn't yet been discarded, keyed by callsign.
+    pub fn all(&self) -> &HashMap<String, Amendment> {
+        &self.amendments
+    }
+
+    /// Forgets any amendment to an aircraft's plan on `network`, e.g. once its pilot has left it.
+    pub fn remove(&mut self, callsign: &str, network: Network) {
+        let callsign = callsign.to_uppercase();
+        if self.get(&callsign, network).is_some() {
+            self.amendments.remove(&callsign);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{network::NetworkProvider, vatsim::VatsimProvider};
+
+    fn network_plan() -> (String, FlightPlan) {
+        let data = VatsimProvider::default().parse(include_str!("../tests/fixtures/vatsim-data.json")).unwrap().unwrap();
+        let pilot = data.pilots.into_iter().find(|pilot| pilot.flight_plan.is_some()).unwrap();
+        (pilot.callsign, pilot.flight_plan.unwrap())
+    }
+
+    fn route(flight_plan: Option<fsd_interface::FlightPlan>) -> String {
+        flight_plan.unwrap().route
+    }
+
+    #[test]
+    fn amendments_belong_to_a_network() {
+        let (callsign, vatsim_plan) = network_plan();
+        let ivao_plan = FlightPlan { revision_id: vatsim_plan.revision_id + 5, route: String::from("UMLAT T418 WELIN"), ..vatsim_plan.clone() };
+        let mut amended = vatsim_plan.clone();
+        amended.route = String::from("DCT");
+        let mut overlay = AmendmentOverlay::default();
+        let msg = FlightPlanAmendmentMessage::new("EGLL_TWR", "SERVER", &callsign, fsd_interface::FlightPlan::from(amended));
+        assert_eq!(overlay.amend(&msg, Network::Vatsim, Some(vatsim_plan.revision_id)), 1);
+        assert_eq!(overlay.amend(&msg, Network::Vatsim, Some(vatsim_plan.revision_id)), 2);
+
+        // A pilot with the same callsign on another network has their own plan, which doesn't displace the amendment
+        assert_eq!(route(overlay.apply(&callsign, Network::Ivao, Some(ivao_plan))), "UMLAT T418 WELIN");
+        assert!(overlay.get(&callsign, Network::Ivao).is_none());
+        overlay.remove(&callsign, Network::Ivao);
+        assert_eq!(route(overlay.apply(&callsign, Network::Vatsim, Some(vatsim_plan.clone()))), "DCT");
+        assert_eq!(overlay.get(&callsign, Network::Vatsim).map(|amendment| amendment.revision), Some(2));
+
+        overlay.remove(&callsign, Network::Vatsim);
+        assert!(overlay.all().is_empty());
+        assert_eq!(route(overlay.apply(&callsign, Network::Vatsim, Some(vatsim_plan.clone()))), vatsim_plan.route);
+    }
+}
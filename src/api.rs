@@ -0,0 +1,372 @@
+use std::{collections::HashMap, sync::{mpsc::{self, Receiver, RecvTimeoutError, Sender}, Arc, Mutex}, thread::{self, JoinHandle}, time::Duration};
+
+use fsd_interface::messages::{AtcPositionUpdateMessage, AtcRegisterMessage};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::{aircraft::Aircraft, amendments::Amendment, feed::Feed, flight_plan::NormalisedFlightPlan, matcher::UnmatchedAircraft, server::SessionStats, worker::{unix_timestamp, WorkerHandle, WorkerStatus}};
+
+const WEBSOCKET_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+
+
+/// Embedded HTTP server which exposes the current traffic picture as JSON, plus a WebSocket
+/// stream of traffic events.
+///
+/// Endpoints:
+/// - `GET /api/traffic` - all targets currently being sent to the ATC client
+/// - `GET /api/own-aircraft` - the user's own aircraft, or `null`
+/// - `GET /api/unmatched` - simulator aircraft with no matching network pilot, and the callsigns tried
+/// - `GET /api/metars` - the METAR table, keyed by ICAO code
+/// - `GET /api/flight-plan?callsign=` - the parsed flight plan for a callsign, with any validation warnings
+///   and the local revision of any controller amendment
+/// - `GET /api/health` - worker refresh status and ATC client connection statistics
+/// - `GET /api/stream` - WebSocket stream of [`ApiEvent`]s
+/// - `GET /v3/vatsim-data.json` - the same picture in VATSIM's v3 data feed format, see [`Feed`]
+/// - `GET /metar.php?id=` - plain-text METARs in the same format as `metar.vatsim.net`, see [`metar_query`]
+/// - `GET /status.json` - a VATSIM-style status document pointing at the two feeds above
+pub struct ApiServer {
+    http: Arc<tiny_http::Server>,
+    thread: Option<JoinHandle<()>>,
+    state: Arc<Mutex<ApiState>>,
+}
+
+impl ApiServer {
+    pub fn start(bind_address: &str, worker: WorkerHandle) -> Result<ApiServer, Box<dyn std::error::Error>> {
+        let http = Arc::new(tiny_http::Server::http(bind_address).map_err(|e| e.to_string())?);
+        let state = Arc::new(Mutex::new(ApiState::default()));
+        let thread = Some(api_thread(Arc::clone(&http), Arc::clone(&state), worker));
+        println!("API listening on http://{}", bind_address);
+        Ok(ApiServer {
+            http,
+            thread,
+            state,
+        })
+    }
+
+    /// Replaces the traffic picture, notifying any WebSocket subscribers of what has changed.
+    pub fn publish_traffic(&self, traffic: Vec<Aircraft>) {
+        let mut state = self.state.lock().unwrap();
+        let mut new_traffic = HashMap::with_capacity(traffic.len());
+        let mut events = vec![];
+        let now = unix_timestamp();
+
+        for aircraft in traffic {
+            match state.traffic.get(&aircraft.callsign) {
+                None => {
+                    state.logon_times.insert(aircraft.callsign.clone(), now);
+                    events.push(ApiEvent::Add(aircraft.clone()));
+                },
+                Some(existing) if *existing != aircraft => events.push(ApiEvent::Update(aircraft.clone())),
+                Some(_) => {},
+            }
+            new_traffic.insert(aircraft.callsign.clone(), aircraft);
+        }
+        for callsign in state.traffic.keys() {
+            if !new_traffic.contains_key(callsign) {
+                events.push(ApiEvent::Remove { callsign: callsign.clone() });
+            }
+        }
+
+        state.logon_times.retain(|callsign, _| new_traffic.contains_key(callsign));
+        state.traffic = new_traffic;
+        for event in events {
+            state.broadcast(event);
+        }
+    }
+
+    pub fn publish_own_aircraft(&self, own_aircraft: Option<Aircraft>) {
+        self.state.lock().unwrap().own_aircraft = own_aircraft;
+    }
+
+    pub fn controller_logged_on(&self, message: &AtcRegisterMessage) {
+        let controller = LocalController {
+            callsign: message.from.clone(),
+            cid: message.cid.clone(),
+            name: message.real_name.clone(),
+            rating: message.rating as u8,
+            facility: 0,
+            frequency: String::from("199.998"),
+            visual_range: 0,
+            lat: 0.0,
+            lon: 0.0,
+            logon_time: unix_timestamp(),
+        };
+        self.state.lock().unwrap().controllers.insert(controller.callsign.clone(), controller);
+    }
+
+    pub fn controller_position(&self, message: &AtcPositionUpdateMessage) {
+        if let Some(controller) = self.state.lock().unwrap().controllers.get_mut(&message.callsign) {
+            controller.facility = message.atc_type as u8;
+            controller.rating = message.rating as u8;
+            controller.visual_range = message.vis_range;
+            controller.lat = message.latitude;
+            controller.lon = message.longitude;
+            if let Some(frequency) = message.frequencies.first() {
+                controller.frequency = frequency.to_human_readable_string();
+            }
+        }
+    }
+
+    pub fn controller_logged_off(&self, callsign: &str) {
+        self.state.lock().unwrap().controllers.remove(callsign);
+    }
+
+    /// Replaces the list of simulator aircraft which couldn't be matched to a network pilot.
+    pub fn publish_unmatched(&self, mut unmatched: Vec<UnmatchedAircraft>) {
+        unmatched.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+        self.state.lock().unwrap().unmatched = unmatched;
+    }
+
+    /// Replaces the controllers' flight plan amendments, keyed by callsign.
+    pub fn publish_amendments(&self, amendments: HashMap<String, Amendment>) {
+        self.state.lock().unwrap().amendments = amendments;
+    }
+
+    /// Replaces the connection statistics of the ATC client sessions.
+    pub fn publish_sessions(&self, sessions: Vec<SessionStats>) {
+        self.state.lock().unwrap().sessions = sessions;
+    }
+}
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.http.unblock();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+
+/// An event sent to WebSocket subscribers when the traffic picture changes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ApiEvent {
+    Add(Aircraft),
+    Update(Aircraft),
+    Remove { callsign: String },
+}
+
+/// An ATC client connected to Traffic Viewer. Timestamps are seconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalController {
+    pub callsign: String,
+    pub cid: String,
+    pub name: String,
+    pub rating: u8,
+    pub facility: u8,
+    pub frequency: String,
+    pub visual_range: u32,
+    pub lat: f64,
+    pub lon: f64,
+    pub logon_time: u64,
+}
+
+#[derive(Default)]
+struct ApiState {
+    traffic: HashMap<String, Aircraft>,
+    logon_times: HashMap<String, u64>,
+    own_aircraft: Option<Aircraft>,
+    controllers: HashMap<String, LocalController>,
+    unmatched: Vec<UnmatchedAircraft>,
+    amendments: HashMap<String, Amendment>,
+    sessions: Vec<SessionStats>,
+    subscribers: Vec<Sender<ApiEvent>>,
+}
+impl ApiState {
+    fn broadcast(&mut self, event: ApiEvent) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn sorted_traffic(&self) -> Vec<&Aircraft> {
+        let mut traffic: Vec<&Aircraft> = self.traffic.values().collect();
+        traffic.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+        traffic
+    }
+
+    fn render_feed(&self, worker: &WorkerHandle) -> serde_json::Result<String> {
+        let traffic: Vec<(&Aircraft, u64)> = self.sorted_traffic().into_iter()
+            .map(|aircraft| (aircraft, self.logon_times.get(&aircraft.callsign).copied().unwrap_or_default()))
+            .collect();
+        let mut controllers: Vec<&LocalController> = self.controllers.values().collect();
+        controllers.sort_by(|a, b| a.callsign.cmp(&b.callsign));
+        serde_json::to_string(&Feed::render(&traffic, self.own_aircraft.as_ref(), &controllers, worker))
+    }
+}
+
+#[derive(Serialize)]
+struct FlightPlanResponse {
+    #[serde(flatten)]
+    flight_plan: NormalisedFlightPlan,
+    /// The network's plan is shown as filed; a controller's amendment replaces it for the ATC clients
+    amendment: Option<Amendment>,
+}
+
+#[derive(Serialize)]
+struct Health {
+    worker: WorkerStatus,
+    traffic_count: usize,
+    subscriber_count: usize,
+    sessions: Vec<SessionStats>,
+}
+
+
+fn api_thread(http: Arc<tiny_http::Server>, state: Arc<Mutex<ApiState>>, worker: WorkerHandle) -> JoinHandle<()> {
+    thread::Builder::new().name(String::from("TrafficViewerApiThread")).spawn(move|| {
+        for request in http.incoming_requests() {
+            if *request.method() != Method::Get {
+                request.respond(Response::empty(StatusCode(405))).ok();
+                continue;
+            }
+            let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+            let (path, query) = (path.to_owned(), query.to_owned());
+            let body = match path.as_str() {
+                "/api/traffic" => serde_json::to_string(&state.lock().unwrap().sorted_traffic()),
+                "/api/own-aircraft" => serde_json::to_string(&state.lock().unwrap().own_aircraft),
+                "/api/unmatched" => serde_json::to_string(&state.lock().unwrap().unmatched),
+                "/api/metars" => serde_json::to_string(&worker.metars()),
+                "/api/flight-plan" => {
+                    let callsign = query_param(&query, "callsign").unwrap_or_default();
+                    match worker.details(&callsign).and_then(|details| details.flight_plan) {
+                        Some(flight_plan) => serde_json::to_string(&FlightPlanResponse {
+                            flight_plan: flight_plan.normalise(),
+                            amendment: state.lock().unwrap().amendments.get(&callsign.to_uppercase()).cloned(),
+                        }),
+                        None => {
+                            request.respond(Response::empty(StatusCode(404))).ok();
+                            continue;
+                        },
+                    }
+                },
+                "/api/health" => {
+                    let state = state.lock().unwrap();
+                    serde_json::to_string(&Health {
+                        worker: worker.status(),
+                        traffic_count: state.traffic.len(),
+                        subscriber_count: state.subscribers.len(),
+                        sessions: state.sessions.clone(),
+                    })
+                },
+                "/v3/vatsim-data.json" => state.lock().unwrap().render_feed(&worker),
+                "/status.json" => {
+                    let host = request.headers().iter().find(|header| header.field.equiv("Host")).map(|header| header.value.to_string()).unwrap_or_default();
+                    serde_json::to_string(&serde_json::json!({
+                        "data": { "v3": [format!("http://{}/v3/vatsim-data.json", host)] },
+                        "metar": [format!("http://{}/metar.php", host)],
+                    }))
+                },
+                "/metar.php" => {
+                    let metars = metar_query(&worker, query_param(&query, "id").unwrap_or_default());
+                    request.respond(Response::from_string(metars.join("\n")).with_header(Header::from_bytes("Content-Type", "text/plain").unwrap())).ok();
+                    continue;
+                },
+                "/api/stream" => {
+                    accept_websocket(request, &state);
+                    continue;
+                },
+                _ => {
+                    request.respond(Response::empty(StatusCode(404))).ok();
+                    continue;
+                },
+            };
+            match body {
+                Ok(body) => request.respond(Response::from_string(body).with_header(json_content_type())).ok(),
+                Err(_) => request.respond(Response::empty(StatusCode(500))).ok(),
+            };
+        }
+    }).unwrap()
+}
+
+/// Resolves a `metar.php?id=` query. As with `metar.vatsim.net`, `id` may be a single station, a
+/// comma-separated list of stations, `all`, or a prefix such as `EG` (optionally written `EG*`)
+/// which matches every station starting with it.
+fn metar_query(worker: &WorkerHandle, id: String) -> Vec<String> {
+    let mut metars = vec![];
+    for id in id.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        if id.eq_ignore_ascii_case("all") {
+            return worker.metars_with_prefix("");
+        }
+        let prefix = id.trim_end_matches('*');
+        if prefix.len() < 4 || prefix.len() != id.len() {
+            metars.extend(worker.metars_with_prefix(prefix));
+        } else {
+            metars.extend(worker.metars_with_prefix(id).into_iter().filter(|metar| metar.split_whitespace().next().is_some_and(|icao| icao.eq_ignore_ascii_case(id))));
+        }
+    }
+    metars
+}
+
+/// Returns the percent-decoded value of a query string parameter.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query.split('&').find_map(|pair| pair.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value))?;
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [iter.next().unwrap_or_default(), iter.next().unwrap_or_default()];
+                bytes.push(std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()).unwrap_or_default());
+            },
+            _ => bytes.push(byte),
+        }
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn accept_websocket(request: Request, state: &Arc<Mutex<ApiState>>) {
+    let key = request.headers().iter().find(|header| header.field.equiv("Sec-WebSocket-Key")).map(|header| header.value.to_string());
+    let key = match key {
+        Some(key) => key,
+        None => {
+            request.respond(Response::empty(StatusCode(400))).ok();
+            return;
+        },
+    };
+
+    let response = Response::empty(StatusCode(101))
+        .with_header(Header::from_bytes("Upgrade", "websocket").unwrap())
+        .with_header(Header::from_bytes("Connection", "Upgrade").unwrap())
+        .with_header(Header::from_bytes("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes())).unwrap());
+    let stream = request.upgrade("websocket", response);
+
+    // Subscribe before taking the snapshot so that no event can fall between the two
+    let (tx, rx) = mpsc::channel();
+    let snapshot = {
+        let mut state = state.lock().unwrap();
+        state.subscribers.push(tx);
+        state.sorted_traffic().into_iter().cloned().map(ApiEvent::Add).collect::<Vec<_>>()
+    };
+    websocket_thread(WebSocket::from_raw_socket(stream, Role::Server, None), snapshot, rx);
+}
+
+fn websocket_thread<S: std::io::Read + std::io::Write + Send + 'static>(mut websocket: WebSocket<S>, snapshot: Vec<ApiEvent>, receiver: Receiver<ApiEvent>) {
+    thread::Builder::new().name(String::from("TrafficViewerWebSocketThread")).spawn(move|| {
+        for event in snapshot {
+            if !send_event(&mut websocket, &event) {
+                return;
+            }
+        }
+        loop {
+            let sent = match receiver.recv_timeout(WEBSOCKET_PING_INTERVAL) {
+                Ok(event) => send_event(&mut websocket, &event),
+                Err(RecvTimeoutError::Timeout) => websocket.send(Message::Ping(vec![])).is_ok(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if !sent {
+                break;
+            }
+        }
+        websocket.close(None).ok();
+    }).ok();
+}
+
+fn send_event<S: std::io::Read + std::io::Write>(websocket: &mut WebSocket<S>, event: &ApiEvent) -> bool {
+    let text = serde_json::to_string(event).unwrap_or_default();
+    websocket.send(Message::Text(text)).is_ok()
+}
+
+fn json_content_type() -> Header {
+    Header::from_bytes("Content-Type", "application/json").unwrap()
+}
@@ -1,14 +1,71 @@
-use std::{ffi::CStr, net::TcpListener, ops::{Div, Mul}, thread, time::Duration};
+use std::{collections::{HashMap, HashSet}, ops::{Div, Mul}, sync::mpsc::Receiver, thread, time::{Duration, Instant}};
 
-use fsd_interface::{messages::{FlightPlanMessage, MetarRequestMessage, PilotPositionUpdateMessage, TextMessage}, FsdMessageType, PilotRating, TransponderCode, TransponderMode};
+use fsd_interface::{messages::{AtcPositionUpdateMessage, ClientQueryMessage, ClientQueryResponseMessage, FlightPlanMessage, MetarRequestMessage, PilotPositionUpdateMessage, TextMessage}, AtcRating, AtcType, AtisLine, ClientQueryType, FsdMessageType, PilotRating, RadioFrequency, TransponderCode, TransponderMode};
 
-use crate::{fsuipc, server::Server, worker::Worker};
+use crate::{aircraft::{Aircraft, TrafficSource}, amendments::AmendmentOverlay, api::ApiServer, atc_state::AtcStateStore, atis::{self, AtisGenerator}, commands::{self, Command, FilterCommand}, config::{Config, MetarConfig, NetworkConfig}, controllers::ControllerMirror, fsuipc, matcher::CallsignMatcher, network::Network, server::Server, vatsim::FlightPlan, weather::{MetarSource, SimWeatherReader, StationMetar}, worker::{self, NetworkEvent, Worker}};
 
 const SERVER_CALLSIGN: &str = "SERVER";
+const METAR_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many aircraft `.list` replies with, so busy areas don't flood the client with messages
+const LIST_LIMIT: usize = 20;
+
+
+/// One online network's data, and what has been sent to the sessions using it.
+struct NetworkContext {
+    worker: Worker,
+    events: Receiver<NetworkEvent>,
+    flight_plans: FlightPlanBacklog,
+    controller_mirror: ControllerMirror,
+    /// The traffic as last sent to the network's sessions
+    last_traffic: Vec<Aircraft>,
+    last_unmatched: usize,
+}
+impl NetworkContext {
+    fn start(metar_config: Option<&MetarConfig>, network_config: &NetworkConfig) -> NetworkContext {
+        let worker = Worker::start(metar_config, network_config);
+        NetworkContext {
+            events: worker.subscribe(),
+            worker,
+            flight_plans: FlightPlanBacklog::default(),
+            controller_mirror: ControllerMirror::default(),
+            last_traffic: vec![],
+            last_unmatched: 0,
+        }
+    }
+}
 
+/// The flight plans which the sessions using a network haven't had yet.
+#[derive(Default)]
+struct FlightPlanBacklog {
+    /// Flight plans which are new or have changed, which none of the sessions have had
+    pending: HashSet<String>,
+    /// Flight plans which were sent before a session logged in, keyed by the session's callsign
+    sessions: HashMap<String, HashSet<String>>,
+}
+impl FlightPlanBacklog {
+    /// Sends an aircraft's flight plan, with any amendment, to the network's sessions which need it: all of
+    /// them if it's new or has changed, otherwise just those which have logged in since it was last sent.
+    fn send(&mut self, server: &mut Server, network: Network, amendments: &mut AmendmentOverlay, callsign: &str, network_plan: Option<FlightPlan>) {
+        let everyone = self.pending.remove(callsign);
+        let sessions: Vec<String> = self.sessions.iter_mut().filter_map(|(session, backlog)| backlog.remove(callsign).then(|| session.clone())).collect();
+        if !everyone && sessions.is_empty() {
+            return;
+        }
+        let Some(flight_plan) = amendments.apply(callsign, network, network_plan) else {
+            return;
+        };
+        match everyone {
+            true => server.broadcast_to_each_on_network(network, |to| FlightPlanMessage::new(to, callsign, flight_plan.clone()).to_string()),
+            false => for session in sessions {
+                server.send_to(&session, &FlightPlanMessage::new(&session, callsign, flight_plan.clone()).to_string());
+            },
+        }
+    }
+}
 
 pub fn run() -> Result<(), Box<dyn std::error::Error>> {
     println!("Traffic Viewer starting");
+    let config = Config::load()?;
 
     // Establish a connection to FSUIPC
     println!("Attempting to connect to simulator");
@@ -27,37 +84,232 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
     };
     println!("Connected to {} via FSUIPC {}", versions.fs_version, versions.fsuipc_version);
 
-    // Start the worker thread which periodically downloads METARs and aircraft data from VATSIM
-    let mut worker = Worker::start();
-
-    // Wait for a connection from a controller client
-    let tcp_listener = TcpListener::bind("127.0.0.1:6809").unwrap();
-    println!("Waiting for ATC client connection...");
-    let (tcp_stream, _) = tcp_listener.accept().unwrap();
+    // Start a worker thread for each network the sessions may use, which periodically downloads its aircraft data.
+    // The default network's worker also downloads METARs from VATSIM. Every session's network has a context.
+    let default_network = config.network.provider;
+    let mut networks = HashMap::from([(default_network, NetworkContext::start(Some(&config.metar), &config.network))]);
+    for network in config.server.accounts.iter().filter_map(|account| account.network) {
+        networks.entry(network).or_insert_with(|| NetworkContext::start(None, &NetworkConfig { provider: network, data_url: None }));
+    }
 
-    println!("Incoming connection request", );
+    // Start the local API so that web-based tools can follow the traffic picture. It's optional, so
+    // Traffic Viewer carries on without it if it can't start, e.g. because the port is in use.
+    let api = match config.api.enabled {
+        true => match ApiServer::start(&config.api.bind_address, networks[&default_network].worker.handle()) {
+            Ok(api) => Some(api),
+            Err(e) => {
+                println!("Couldn't start the API on {}, continuing without it: {}", config.api.bind_address, e);
+                None
+            },
+        },
+        false => None,
+    };
 
-    // Once we have a connection from a controller client, we can refresh the metars and vatsim data
-    worker.refresh_metars();
-    worker.refresh_vatsim_data();
+    // Accept connections from controller clients
+    let mut server = Server::start(&config.server, &config.capture, default_network)?;
 
     let mut count = 0;
-    let mut server = Server::new(tcp_stream);
-
-    let mut client_cs = None;
+    let mut sim_weather = SimWeatherReader::default();
+    let mut atis_generator = AtisGenerator::new(&config.atis);
+    let mut matcher = CallsignMatcher::new(&config.matcher);
+    let mut atc_state = AtcStateStore::default();
+    let mut amendments = AmendmentOverlay::default();
+    let mut client_position = (0.0, 0.0);
+    let mut pending_metar_requests: Vec<(String, String, Instant)> = vec![];
+    // The traffic settings can be changed with the .filter command
+    let mut traffic_config = config.traffic.clone();
 
     loop {
-        worker.tick();
-        for message in server.poll() {
+        // The metars only need refreshing while a controller client is connected, and each network's data while
+        // a client is using it
+        for (network, context) in networks.iter_mut() {
+            let in_use = match *network == default_network {
+                true => server.has_sessions(),
+                false => !server.callsigns_on(*network).is_empty(),
+            };
+            context.worker.set_idle(!in_use);
+            context.worker.tick();
+        }
+        pending_metar_requests.retain(|(from, station, requested)| {
+            match lookup_metar(config.weather.metar_source, &networks[&default_network].worker, &mut sim_weather, station) {
+                Some(metar) => {
+                    server.send_to(from, &MetarRequestMessage::new("server", from, metar).to_string());
+                    false
+                },
+                None => requested.elapsed() < METAR_REQUEST_TIMEOUT,
+            }
+        });
+        let messages = server.poll();
+        // The first client to log in is the one whose own aircraft is shown
+        let client_cs = server.callsigns().into_iter().next();
+        for message in messages {
+            atc_state.update(&message, |controller| server.network_of(controller).unwrap_or(default_network));
             match message {
                 FsdMessageType::AtcRegisterMessage(msg) => {
-                    client_cs = Some(msg.from.clone());
-                    server.send_packet(&TextMessage::new(SERVER_CALLSIGN, msg.from, "Connected to Traffic Viewer. Welcome!").to_string());
+                    let context = networks.get_mut(&server.network_of(&msg.from).unwrap_or(default_network)).unwrap();
+                    if let Some(api) = &api {
+                        api.controller_logged_on(&msg);
+                    }
+                    let welcome = match server.is_observer(&msg.from) {
+                        true => "Connected to Traffic Viewer as an observer. Welcome!",
+                        false => "Connected to Traffic Viewer. Welcome!",
+                    };
+                    server.send_to(&msg.from, &TextMessage::new(SERVER_CALLSIGN, &msg.from, welcome).to_string());
+                    for packet in context.controller_mirror.announcements().into_iter().chain(atc_state.replay()) {
+                        server.send_to(&msg.from, &packet);
+                    }
+                    // A controller which has reconnected takes back the aircraft it was tracking
+                    for packet in atc_state.replay_owned_by(&msg.from) {
+                        server.broadcast_except(&msg.from, &packet);
+                    }
+                    // The new client hasn't had any flight plans yet, prefiles included, which the others already have
+                    context.flight_plans.sessions.insert(msg.from.clone(), context.worker.flight_plan_callsigns());
                 },
                 FsdMessageType::MetarRequestMessage(msg) => {
-                    if let Some(metar) = worker.get_metar(&msg.station) {
-                        let res = server.send_packet(&MetarRequestMessage::new("server", msg.from, metar).to_string());
+                    if let Some(metar) = lookup_metar(config.weather.metar_source, &networks[&default_network].worker, &mut sim_weather, &msg.station) {
+                        let res = server.send_to(&msg.from, &MetarRequestMessage::new("server", &msg.from, metar).to_string());
                         println!("Sent METAR message: {}", res);
+                    } else {
+                        // The worker fetches missing stations on their own, so the METAR may turn up shortly
+                        pending_metar_requests.push((msg.from, msg.station, Instant::now()));
+                    }
+                },
+                FsdMessageType::ClientQueryMessage(msg) => {
+                    let network = server.network_of(&msg.from).unwrap_or(default_network);
+                    let context = &networks[&network];
+                    match msg.query_type {
+                    ClientQueryType::ATIS => {
+                        let lines = match atis_generator.airport_for_callsign(&msg.to) {
+                            Some(icao) => atis_generator.atis(icao, context.worker.get_atis(&msg.to)),
+                            None => context.controller_mirror.get(&msg.to).map(|controller| controller.text_atis.clone().unwrap_or_default()),
+                        };
+                        if let Some(lines) = lines {
+                            for line in lines.iter() {
+                                server.send_to(&msg.from, &ClientQueryResponseMessage::atis(&msg.to, &msg.from, AtisLine::TextLine(line.clone())).to_string());
+                            }
+                            server.send_to(&msg.from, &ClientQueryResponseMessage::atis(&msg.to, &msg.from, AtisLine::EndMarker(lines.len())).to_string());
+                        }
+                    },
+                    ClientQueryType::FlightPlan(callsign) => {
+                        if let Some(flight_plan) = amendments.apply(&callsign, network, context.worker.get_flight_plan(&callsign)) {
+                            server.send_to(&msg.from, &FlightPlanMessage::new(&msg.from, &callsign, flight_plan).to_string());
+                        }
+                    },
+                    ClientQueryType::RealName => {
+                        if let Some(controller) = context.controller_mirror.get(&msg.to) {
+                            server.send_to(&msg.from, &ClientQueryResponseMessage::real_name(&msg.to, &msg.from, &controller.name, "", controller.rating.max(1) as u8).to_string());
+                        }
+                    },
+                    _ => {},
+                    }
+                },
+                FsdMessageType::AtcPositionUpdateMessage(msg) => {
+                    if Some(&msg.callsign) == client_cs.as_ref() {
+                        client_position = (msg.latitude, msg.longitude);
+                    }
+                    if let Some(api) = &api {
+                        api.controller_position(&msg);
+                    }
+                },
+                FsdMessageType::FlightPlanAmendmentMessage(msg) => {
+                    let network = server.network_of(&msg.from).unwrap_or(default_network);
+                    let base_revision = networks[&network].worker.get_flight_plan(&msg.callsign).map(|flight_plan| flight_plan.revision_id);
+                    let revision = amendments.amend(&msg, network, base_revision);
+                    println!("{} amended {}'s flight plan (local revision {})", msg.from, msg.callsign, revision);
+                    if let Some(api) = &api {
+                        api.publish_amendments(amendments.all().clone());
+                    }
+                    server.broadcast_except(&msg.from, &FlightPlanMessage::new("*A", &msg.callsign, msg.flight_plan.clone()).to_string());
+                },
+                FsdMessageType::TextMessage(msg) if msg.to.eq_ignore_ascii_case(SERVER_CALLSIGN) => {
+                    let network = server.network_of(&msg.from).unwrap_or(default_network);
+                    let context = &networks[&network];
+                    let replies: Vec<String> = match commands::parse(&msg.message) {
+                        None => vec![String::from("Commands start with a full stop, send .help for a list")],
+                        Some(Err(usage)) => vec![usage],
+                        Some(Ok(command)) if command.is_mutating() && server.is_observer(&msg.from) => vec![String::from("Observers can't change settings")],
+                        Some(Ok(Command::Help)) => commands::HELP.iter().map(|line| line.to_string()).collect(),
+                        Some(Ok(Command::Status)) => {
+                            let status = context.worker.handle().status();
+                            let metar_status = networks[&default_network].worker.handle().status();
+                            let simulator = context.last_traffic.iter().filter(|aircraft| aircraft.source == TrafficSource::Simulator).count();
+                            let mut replies = vec![
+                                format!("{} data {} pilots, updated {}", network.name(), status.pilot_count, describe_age(status.network_data_last_updated)),
+                                format!("METARs {} stations, updated {}", metar_status.metar_count, describe_age(metar_status.metars_last_updated)),
+                            ];
+                            replies.extend(status.network_data_last_error.map(|error| format!("Last network data error {}", error)));
+                            replies.extend(metar_status.metars_last_error.map(|error| format!("Last METAR error {}", error)));
+                            replies.push(format!("Sessions {}", server.callsigns().join(", ")));
+                            replies.push(format!("Traffic {} from the simulator, {} from the network, {} unmatched", simulator, context.last_traffic.len() - simulator, context.last_unmatched));
+                            replies
+                        },
+                        Some(Ok(Command::List)) if context.last_traffic.is_empty() => vec![String::from("No traffic")],
+                        Some(Ok(Command::List)) => {
+                            let mut replies: Vec<String> = context.last_traffic.iter().take(LIST_LIMIT).map(|aircraft| {
+                                let source = match aircraft.source {
+                                    TrafficSource::Simulator => "simulator",
+                                    TrafficSource::Network => "network",
+                                };
+                                match &aircraft.owner {
+                                    Some(owner) => format!("{} {:.0}ft from the {}, tracked by {}", aircraft.callsign, aircraft.alt, source, owner),
+                                    None => format!("{} {:.0}ft from the {}", aircraft.callsign, aircraft.alt, source),
+                                }
+                            }).collect();
+                            if context.last_traffic.len() > LIST_LIMIT {
+                                replies.push(format!("and {} more", context.last_traffic.len() - LIST_LIMIT));
+                            }
+                            replies
+                        },
+                        Some(Ok(Command::MetarRefresh)) => {
+                            networks.get_mut(&default_network).unwrap().worker.refresh_metars();
+                            vec![String::from("Refreshing METARs")]
+                        },
+                        Some(Ok(Command::Filter(filter))) => {
+                            match filter {
+                                FilterCommand::Show => {},
+                                FilterCommand::Range(range) => traffic_config.network_pilot_range = range,
+                                FilterCommand::Network(forward) => traffic_config.forward_network_pilots = forward,
+                            }
+                            if filter != FilterCommand::Show {
+                                println!("{} changed the traffic filter to {:?}", msg.from, traffic_config);
+                            }
+                            vec![match traffic_config.forward_network_pilots {
+                                true => format!("Network pilots are forwarded within {} NM", traffic_config.network_pilot_range),
+                                false => format!("Network pilots aren't forwarded (range {} NM)", traffic_config.network_pilot_range),
+                            }]
+                        },
+                        Some(Ok(Command::Match { sim, network })) => {
+                            matcher.set_override(&sim, network.as_deref());
+                            println!("{} matched {} to {:?}", msg.from, sim, network);
+                            match network {
+                                Some(network) if !context.worker.has_details(&network) => vec![format!("{} now matches {}, who isn't on the network at the moment", sim, network)],
+                                Some(network) => vec![format!("{} now matches {}", sim, network)],
+                                None => vec![format!("Cleared the match for {}", sim)],
+                            }
+                        },
+                        Some(Ok(Command::FlightPlan(callsign))) => match amendments.apply(&callsign, network, context.worker.get_flight_plan(&callsign)) {
+                            Some(flight_plan) => {
+                                let mut replies = vec![
+                                    format!("{} {:?} {} {}-{} alternate {}", callsign, flight_plan.flight_rules, flight_plan.ac_type, flight_plan.origin, flight_plan.destination, flight_plan.alternate),
+                                    format!("Level {} TAS {}", flight_plan.cruise_level, flight_plan.filed_tas),
+                                    format!("Route {}", flight_plan.route),
+                                ];
+                                replies.extend(amendments.get(&callsign, network).map(|amendment| format!("Amended by {} (local revision {})", amendment.amended_by, amendment.revision)));
+                                replies
+                            },
+                            None => vec![format!("No flight plan for {}", callsign)],
+                        },
+                    };
+                    for reply in replies {
+                        server.send_to(&msg.from, &TextMessage::new(SERVER_CALLSIGN, &msg.from, reply.replace(':', " ")).to_string());
+                    }
+                },
+                FsdMessageType::AtcDeregisterMessage(msg) => {
+                    for context in networks.values_mut() {
+                        context.flight_plans.sessions.remove(&msg.from);
+                    }
+                    if let Some(api) = &api {
+                        api.controller_logged_off(&msg.from);
                     }
                 },
                 _ => {},
@@ -66,53 +318,147 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
 
         // Aircraft
         if count == 0 {
+            // ATIS stations, so that they appear in the client's controller list
+            let airports: Vec<_> = atis_generator.airports().map(|(icao, airport)| (icao.clone(), airport.clone())).collect();
+            for (icao, airport) in airports {
+                let callsign = format!("{}_ATIS", icao);
+                if let Some(metar) = lookup_metar(config.weather.metar_source, &networks[&default_network].worker, &mut sim_weather, &icao) {
+                    if let Some(letter) = atis_generator.update(&icao, &metar) {
+                        let (wind, pressure) = atis::wind_and_pressure(&metar);
+                        server.broadcast_to_each(|to| ClientQueryMessage::new_atis(&callsign, to, letter, wind.clone(), pressure.clone()).to_string());
+                    }
+                }
+                let frequencies: Vec<RadioFrequency> = RadioFrequency::try_from_human_readable_string(&airport.frequency).into_iter().collect();
+                server.broadcast(&AtcPositionUpdateMessage::new(&callsign, frequencies, AtcType::Tower, 50, AtcRating::Observer, airport.latitude, airport.longitude, 0).to_string());
+            }
+
             let gnd_aircraft = fsuipc::get_aircraft(true);
             let air_aircraft = fsuipc::get_aircraft(false);
+            matcher.reload_overrides();
+
+            // Each network's sessions get the traffic with that network's callsigns and flight plans
+            for (network, context) in networks.iter_mut() {
+                let network = *network;
+
+                // Flight plans are sent the next time their aircraft is, which may be much later
+                for event in context.events.try_iter() {
+                    match event {
+                        NetworkEvent::PilotAdded(callsign) | NetworkEvent::FlightPlanChanged(callsign) => {
+                            context.flight_plans.pending.insert(callsign);
+                        },
+                        NetworkEvent::PilotRemoved(callsign) => {
+                            context.flight_plans.pending.remove(&callsign);
+                            for backlog in context.flight_plans.sessions.values_mut() {
+                                backlog.remove(&callsign);
+                            }
+                            atc_state.remove(&callsign, network);
+                            amendments.remove(&callsign, network);
+                        },
+                        // Every position update carries the current squawk anyway
+                        NetworkEvent::SquawkChanged(_) => {},
+                    }
+                }
+                // The default network's traffic is always worked out, for the API
+                if network != default_network && server.callsigns_on(network).is_empty() {
+                    context.last_traffic.clear();
+                    continue;
+                }
 
-            for tcas_data in gnd_aircraft.iter().chain(air_aircraft.iter()) {
-                let callsign = CStr::from_bytes_until_nul(&tcas_data.atc_id).unwrap();
-                let callsign = match callsign.to_str() {
-                    Ok(cs) => cs,
-                    Err(_) => continue,
-                };
-                if let Some((details, dirty)) = worker.get_aircraft_details(callsign) {
-                    if callsign == "BEL250" {
-                        println!("BEL250 dirty? {} found in details: {:?}", dirty, details);
-                    }
-                    if dirty {
-                        if let Some(flight_plan) = details.flight_plan {
-                            let flight_plan = fsd_interface::FlightPlan::from(flight_plan);
-                            let to = client_cs.clone().unwrap_or(String::from("A*"));
-                            server.send_packet(&FlightPlanMessage::new(to, callsign, flight_plan).to_string());
-                        }
+                // Controllers online on the network
+                if config.controllers.mirror {
+                    for packet in context.controller_mirror.update(context.worker.get_controllers(), &server.callsigns(), client_position) {
+                        server.broadcast_to_network(network, &packet);
                     }
+                }
 
-                    let alt_diff = ((details.qnh_i_hg - 29.92).mul(100.0).round().div(100.0) * 1000.0) as f64;
+                let worker = &context.worker;
+                let mut traffic = Vec::with_capacity(gnd_aircraft.len() + air_aircraft.len());
+                let mut unmatched = vec![];
+
+                for tcas_data in gnd_aircraft.iter().chain(air_aircraft.iter()) {
+                    // A callsign which fills the whole field has been truncated and has no terminator
+                    let length = tcas_data.atc_id.iter().position(|b| *b == 0).unwrap_or(tcas_data.atc_id.len());
+                    let sim_callsign = match std::str::from_utf8(&tcas_data.atc_id[..length]) {
+                        Ok(cs) if !cs.trim().is_empty() => cs.trim(),
+                        _ => continue,
+                    };
+                    let callsign = match matcher.resolve(sim_callsign, |cs| worker.has_details(cs)) {
+                        Ok((callsign, _)) => callsign,
+                        Err(aircraft) => {
+                            unmatched.push(aircraft);
+                            continue;
+                        },
+                    };
+                    let callsign = callsign.as_str();
+                    if let Some(details) = worker.get_aircraft_details(callsign) {
+                        context.flight_plans.send(&mut server, network, &mut amendments, callsign, details.flight_plan.clone());
+
+                        let alt_diff = ((details.qnh_i_hg - 29.92).mul(100.0).round().div(100.0) * 1000.0) as f64;
+
+                        let position = PilotPositionUpdateMessage::new(callsign, TransponderMode::ModeC, TransponderCode::try_from(details.transponder.parse::<u16>().unwrap_or_default()).unwrap_or(TransponderCode::try_from(0).unwrap()), PilotRating::Student, tcas_data.lat as f64, tcas_data.lon as f64, tcas_data.alt as f64, tcas_data.alt as f64 - alt_diff, tcas_data.gs as u32, 0.0, 0.0, (tcas_data.hdg as f64 / HDG_FACTOR as f64).floor(), false);
+
+                        server.broadcast_to_network(network, &position.to_string());
+                        traffic.push(Aircraft::from_position(tcas_data.id, &position, TrafficSource::Simulator));
+                    }
+                };
 
-                    let position = PilotPositionUpdateMessage::new(callsign, TransponderMode::ModeC, TransponderCode::try_from(details.transponder.parse::<u16>().unwrap_or_default()).unwrap_or(TransponderCode::try_from(0).unwrap()), PilotRating::Student, tcas_data.lat as f64, tcas_data.lon as f64, tcas_data.alt as f64, tcas_data.alt as f64 - alt_diff, tcas_data.gs as u32, 0.0, 0.0, (tcas_data.hdg as f64 / HDG_FACTOR as f64).floor(), false);
-                    
-                    server.send_packet(&position.to_string());
+                // Network pilots which the simulator hasn't injected, e.g. because they're beyond its traffic radius.
+                // Simulator positions always take precedence. Until the client has sent its position there's nothing
+                // to measure the range from, so none are sent rather than the whole network.
+                if traffic_config.forward_network_pilots && client_position != (0.0, 0.0) {
+                    let in_sim: HashSet<String> = traffic.iter().map(|aircraft| aircraft.callsign.clone()).collect();
+                    for pilot in worker.get_online_pilots() {
+                        if in_sim.contains(&pilot.callsign) || client_cs.as_ref() == Some(&pilot.callsign) {
+                            continue;
+                        }
+                        if distance_nm(client_position, (pilot.latitude, pilot.longitude)) > traffic_config.network_pilot_range {
+                            continue;
+                        }
+                        context.flight_plans.send(&mut server, network, &mut amendments, &pilot.callsign, pilot.flight_plan.clone());
+
+                        let alt_diff = ((pilot.qnh_i_hg - 29.92).mul(100.0).round().div(100.0) * 1000.0) as f64;
+                        let transponder = TransponderCode::try_from(pilot.transponder.parse::<u16>().unwrap_or_default()).unwrap_or_else(|_| TransponderCode::try_from(0).unwrap());
+                        let position = PilotPositionUpdateMessage::new(&pilot.callsign, TransponderMode::ModeC, transponder, PilotRating::Student, pilot.latitude, pilot.longitude, pilot.altitude as f64, pilot.altitude as f64 - alt_diff, pilot.groundspeed, 0.0, 0.0, pilot.heading as f64, false);
+                        server.broadcast_to_network(network, &position.to_string());
+                        traffic.push(Aircraft::from_position(pilot.cid as u32, &position, TrafficSource::Network));
+                    }
                 }
-            };
+                for aircraft in traffic.iter_mut() {
+                    aircraft.owner = atc_state.current_owner(&aircraft.callsign).map(str::to_owned);
+                }
+                context.last_unmatched = unmatched.len();
+                if let (Some(api), true) = (&api, network == default_network) {
+                    api.publish_traffic(traffic.clone());
+                    api.publish_unmatched(unmatched);
+                    api.publish_amendments(amendments.all().clone());
+                }
+                context.last_traffic = traffic;
+            }
+            let present: HashSet<String> = networks.values().flat_map(|context| context.last_traffic.iter().map(|aircraft| aircraft.callsign.clone())).chain(client_cs.clone()).collect();
+            atc_state.release_missing(&present);
+            if let Some(api) = &api {
+                api.publish_sessions(server.stats());
+            }
 
 
             // Own aircraft
+        let mut own_aircraft = None;
         if let Ok(own_aircraft_data) = fsuipc::get_own_aircraft_data() {
             if let Some(callsign) = &client_cs {
-                if let Some((details, dirty)) = worker.get_aircraft_details(callsign) {
-                    if dirty {
-                        if let Some(flight_plan) = details.flight_plan {
-                            let flight_plan = fsd_interface::FlightPlan::from(flight_plan);
-                            let to = client_cs.clone().unwrap_or(String::from("A*"));
-                            server.send_packet(&FlightPlanMessage::new(to, callsign, flight_plan).to_string());
-                        }
-                    }
+                let network = server.network_of(callsign).unwrap_or(default_network);
+                let context = networks.get_mut(&network).unwrap();
+                if let Some(details) = context.worker.get_aircraft_details(callsign) {
+                    context.flight_plans.send(&mut server, network, &mut amendments, callsign, details.flight_plan.clone());
                     let alt_diff = ((details.qnh_i_hg - 29.92).mul(100.0).round().div(100.0) * 1000.0) as f64;
                     let position = PilotPositionUpdateMessage::new(callsign, TransponderMode::ModeC, TransponderCode::try_from(own_aircraft_data.xpdr_str.parse::<u16>().unwrap_or_default()).unwrap_or_else(|_| TransponderCode::try_from(0).unwrap()), PilotRating::Student, own_aircraft_data.lat, own_aircraft_data.lon, own_aircraft_data.alt, own_aircraft_data.alt - alt_diff, own_aircraft_data.gs.floor() as u32, 0.0, 0.0, own_aircraft_data.true_hdg, false);
-                    server.send_packet(&position.to_string());
+                    server.broadcast(&position.to_string());
+                    own_aircraft = Some(Aircraft::from_position(0, &position, TrafficSource::Simulator));
                 }
             }
         }
+        if let Some(api) = &api {
+            api.publish_own_aircraft(own_aircraft);
+        }
 
 
         }
@@ -136,4 +482,78 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
 
 
 
-const HDG_FACTOR: f32 = 182.044444444;
\ No newline at end of file
+const HDG_FACTOR: f32 = 182.044444444;
+
+/// Finds the METAR for a station from whichever source the user has chosen. Gives `None` while the simulator
+/// is still looking a station up, so callers should try again on a later tick.
+fn lookup_metar(source: MetarSource, worker: &Worker, sim_weather: &mut SimWeatherReader, station: &str) -> Option<String> {
+    match source {
+        MetarSource::Vatsim => worker.get_metar(station),
+        MetarSource::Simulator => match sim_weather.station_metar(station) {
+            StationMetar::Available(metar) => Some(metar),
+            StationMetar::Unknown => sim_weather.aircraft_metar(station),
+            StationMetar::Pending => None,
+        },
+        MetarSource::PreferSimulator => match sim_weather.station_metar(station) {
+            StationMetar::Available(metar) => Some(metar),
+            StationMetar::Unknown => worker.get_metar(station),
+            StationMetar::Pending => None,
+        },
+    }
+}
+
+/// How long ago a Unix timestamp was, for a text message.
+fn describe_age(timestamp: Option<u64>) -> String {
+    match timestamp {
+        Some(timestamp) => format!("{}s ago", worker::unix_timestamp().saturating_sub(timestamp)),
+        None => String::from("never"),
+    }
+}
+
+/// Great-circle distance between two positions in degrees, in nautical miles.
+fn distance_nm(from: (f64, f64), to: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_NM: f64 = 3440.065;
+    let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+    let (dlat, dlon) = ((to.0 - from.0).to_radians(), (to.1 - from.1).to_radians());
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_NM * a.sqrt().asin()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, network::NetworkProvider, server::tests::{start_server, TestClient}, vatsim::VatsimProvider, worker::NetworkSnapshot};
+
+    #[test]
+    fn sends_prefiles_to_sessions_which_log_in_later() {
+        let data = VatsimProvider::default().parse(include_str!("../tests/fixtures/vatsim-data.json")).unwrap().unwrap();
+        let prefile = data.prefiles[0].clone();
+        let (snapshot, _) = NetworkSnapshot::next(&NetworkSnapshot::default(), data);
+        let mut server = start_server(ServerConfig::default());
+        let mut amendments = AmendmentOverlay::default();
+        let mut backlog = FlightPlanBacklog::default();
+
+        let (mut tower, _) = TestClient::log_in(&mut server, "EGLL_TWR", "1234567", "password", 3);
+        tower.received();
+        backlog.pending.insert(prefile.callsign.clone());
+        backlog.send(&mut server, Network::Vatsim, &mut amendments, &prefile.callsign, prefile.flight_plan.clone());
+        let published = format!("$FP{}:EGLL_TWR:", prefile.callsign);
+        assert!(tower.receive(&mut server).iter().any(|line| line.starts_with(&published)));
+
+        // A session which logs in afterwards gets it the next time it's sent, and only that session does
+        let (mut approach, _) = TestClient::log_in(&mut server, "EGLL_APP", "7654321", "password", 5);
+        assert!(snapshot.flight_plan_callsigns().contains(&prefile.callsign));
+        backlog.sessions.insert(String::from("EGLL_APP"), snapshot.flight_plan_callsigns());
+        approach.received();
+        tower.received();
+        backlog.send(&mut server, Network::Vatsim, &mut amendments, &prefile.callsign, prefile.flight_plan.clone());
+        let published = format!("$FP{}:EGLL_APP:", prefile.callsign);
+        assert!(approach.receive(&mut server).iter().any(|line| line.starts_with(&published)));
+        assert!(tower.received().is_empty());
+
+        // Once each session has had it, it isn't sent again
+        backlog.send(&mut server, Network::Vatsim, &mut amendments, &prefile.callsign, prefile.flight_plan.clone());
+        assert!(approach.receive(&mut server).is_empty());
+    }
+}
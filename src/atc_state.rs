@@ -0,0 +1,241 @@
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
+
+use fsd_interface::{messages::{ClientQueryMessage, AIRCRAFT_HANDLER_RECIPIENT}, ClientQueryType, FsdMessageType, TransponderCode, VoiceCapability};
+
+use crate::network::Network;
+
+const SERVER_CALLSIGN: &str = "SERVER";
+/// How long an aircraft can be missing from the traffic picture before its assignments are forgotten, so
+/// that they survive e.g. the simulator briefly dropping it
+const RELEASE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+
+
+/// What the controllers have assigned to one aircraft.
+#[derive(Debug, Clone, Default)]
+pub struct AtcState {
+    /// The controller tracking the aircraft
+    pub owner: Option<String>,
+    /// Whether the owner has disconnected. They take the track back if they reconnect, but meanwhile it
+    /// isn't shown or replayed to the other clients.
+    pub owner_stale: bool,
+    pub squawk: Option<TransponderCode>,
+    pub temp_altitude: Option<u32>,
+    pub final_altitude: Option<u32>,
+    pub scratchpad: Option<String>,
+    pub voice_type: Option<VoiceCapability>,
+    /// The network of the controller who made the latest assignment, whose pilot the aircraft is
+    pub network: Network,
+}
+
+
+/// The authoritative record of the assignments the connected controllers have made, so that they
+/// outlive the client which made them.
+#[derive(Default)]
+pub struct AtcStateStore {
+    aircraft: HashMap<String, AtcState>,
+    /// When each aircraft was last in the traffic picture
+    last_seen: HashMap<String, Instant>,
+}
+impl AtcStateStore {
+    /// Records any assignment carried by a packet from a controller, and whether owners are connected.
+    /// `network_of` gives the network whose data a connected controller is using.
+    pub fn update(&mut self, message: &FsdMessageType, network_of: impl Fn(&str) -> Network) {
+        let (controller, query_type) = match message {
+            FsdMessageType::ClientQueryMessage(msg) => (&msg.from, &msg.query_type),
+            FsdMessageType::HandoffAcceptMessage(msg) => {
+                self.set_owner(&msg.aircraft, Some(msg.from.clone()), network_of(&msg.from));
+                return;
+            },
+            FsdMessageType::AtcRegisterMessage(msg) => {
+                self.set_owner_stale(&msg.from, false);
+                return;
+            },
+            FsdMessageType::AtcDeregisterMessage(msg) => {
+                self.set_owner_stale(&msg.from, true);
+                return;
+            },
+            _ => return,
+        };
+        let network = network_of(controller);
+        match query_type {
+            ClientQueryType::InitiateTrack(callsign) => self.set_owner(callsign, Some(controller.clone()), network),
+            ClientQueryType::AcceptHandoff(callsign, new_owner) => self.set_owner(callsign, Some(new_owner.clone()), network),
+            ClientQueryType::DropTrack(callsign) => self.set_owner(callsign, None, network),
+            ClientQueryType::SetBeaconCode(callsign, code) => self.state(callsign, network).squawk = Some(*code),
+            ClientQueryType::SetTempAltitude(callsign, altitude) => self.state(callsign, network).temp_altitude = Some(*altitude),
+            ClientQueryType::SetFinalAltitude(callsign, altitude) => self.state(callsign, network).final_altitude = Some(*altitude),
+            ClientQueryType::SetScratchpad(callsign, contents) => self.state(callsign, network).scratchpad = Some(contents.clone()),
+            ClientQueryType::SetVoiceType(callsign, voice_type) => self.state(callsign, network).voice_type = Some(*voice_type),
+            _ => {},
+        }
+    }
+
+    pub fn get(&self, callsign: &str) -> Option<&AtcState> {
+        self.aircraft.get(&callsign.to_uppercase())
+    }
+
+    /// The controller tracking an aircraft, unless they have disconnected.
+    pub fn current_owner(&self, callsign: &str) -> Option<&str> {
+        self.get(callsign).filter(|state| !state.owner_stale).and_then(|state| state.owner.as_deref())
+    }
+
+    /// Forgets an aircraft once its pilot has left `network`. Assignments made by controllers using another
+    /// network's data are kept, as they're for that network's pilot of the same callsign.
+    pub fn remove(&mut self, callsign: &str, network: Network) {
+        let callsign = callsign.to_uppercase();
+        if self.aircraft.get(&callsign).is_some_and(|state| state.network == network) {
+            self.aircraft.remove(&callsign);
+            self.last_seen.remove(&callsign);
+        }
+    }
+
+    /// Notes which aircraft are in the traffic picture, and forgets those which have been out of it for
+    /// longer than [`RELEASE_AFTER`], e.g. AI aircraft the simulator has removed.
+    pub fn release_missing(&mut self, present: &HashSet<String>) {
+        let now = Instant::now();
+        for callsign in self.aircraft.keys() {
+            match present.contains(callsign) {
+                true => { self.last_seen.insert(callsign.clone(), now); },
+                false => { self.last_seen.entry(callsign.clone()).or_insert(now); },
+            }
+        }
+        let last_seen = &mut self.last_seen;
+        self.aircraft.retain(|callsign, _| last_seen.get(callsign).is_some_and(|seen| now.duration_since(*seen) < RELEASE_AFTER));
+        last_seen.retain(|callsign, _| self.aircraft.contains_key(callsign));
+    }
+
+    /// The packets which bring a client up to date with every assignment. Each is sent as though from the
+    /// aircraft's owner, or from the server for aircraft nobody is tracking.
+    pub fn replay(&self) -> Vec<String> {
+        self.replay_where(|_| true)
+    }
+
+    /// The packets for just the aircraft tracked by `controller`, to announce to the other clients
+    /// when it reconnects. Call after [`AtcStateStore::update`] has seen it register.
+    pub fn replay_owned_by(&self, controller: &str) -> Vec<String> {
+        self.replay_where(|state| state.owner.as_deref().is_some_and(|owner| owner.eq_ignore_ascii_case(controller)))
+    }
+
+    fn replay_where(&self, filter: impl Fn(&AtcState) -> bool) -> Vec<String> {
+        let mut packets = vec![];
+        for (callsign, state) in self.aircraft.iter().filter(|(_, state)| filter(state)) {
+            let owner = state.owner.as_deref().filter(|_| !state.owner_stale);
+            let from = owner.unwrap_or(SERVER_CALLSIGN);
+            if owner.is_some() {
+                packets.push(ClientQueryMessage::initiate_track(from, AIRCRAFT_HANDLER_RECIPIENT, callsign));
+            }
+            if let Some(squawk) = state.squawk {
+                packets.push(ClientQueryMessage::set_beacon_code(from, AIRCRAFT_HANDLER_RECIPIENT, callsign, squawk));
+            }
+            if let Some(altitude) = state.temp_altitude {
+                packets.push(ClientQueryMessage::set_temp_altitude(from, AIRCRAFT_HANDLER_RECIPIENT, callsign, altitude));
+            }
+            if let Some(altitude) = state.final_altitude {
+                packets.push(ClientQueryMessage::set_final_altitude(from, AIRCRAFT_HANDLER_RECIPIENT, callsign, altitude));
+            }
+            if let Some(scratchpad) = &state.scratchpad {
+                packets.push(ClientQueryMessage::set_scratchpad(from, AIRCRAFT_HANDLER_RECIPIENT, callsign, scratchpad.clone()));
+            }
+            if let Some(voice_type) = state.voice_type {
+                packets.push(ClientQueryMessage::set_voice_type(from, AIRCRAFT_HANDLER_RECIPIENT, callsign, voice_type));
+            }
+        }
+        packets.into_iter().map(|packet| packet.to_string()).collect()
+    }
+
+    fn state(&mut self, callsign: &str, network: Network) -> &mut AtcState {
+        let state = self.aircraft.entry(callsign.to_uppercase()).or_default();
+        state.network = network;
+        state
+    }
+
+    fn set_owner(&mut self, callsign: &str, owner: Option<String>, network: Network) {
+        let state = self.state(callsign, network);
+        state.owner = owner;
+        state.owner_stale = false;
+    }
+
+    fn set_owner_stale(&mut self, controller: &str, stale: bool) {
+        for state in self.aircraft.values_mut() {
+            if state.owner.as_deref().is_some_and(|owner| owner.eq_ignore_ascii_case(controller)) {
+                state.owner_stale = stale;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(store: &mut AtcStateStore, line: &str) {
+        store.update(&fsd_interface::parse_message(line).unwrap(), |controller| match controller {
+            "LFPG_TWR" => Network::Ivao,
+            _ => Network::Vatsim,
+        });
+    }
+
+    #[test]
+    fn owners_go_stale_while_disconnected() {
+        let mut store = AtcStateStore::default();
+        update(&mut store, "$CQEGLL_TWR:@94835:IT:BAW123");
+        update(&mut store, "$CQEGLL_TWR:@94835:BC:BAW123:4721");
+        assert_eq!(store.current_owner("baw123"), Some("EGLL_TWR"));
+
+        update(&mut store, "#DAEGLL_TWR:1234567");
+        assert_eq!(store.current_owner("BAW123"), None);
+        assert!(store.get("BAW123").unwrap().owner_stale);
+        // Other clients are still given the squawk, but not the track
+        let replay = store.replay();
+        assert_eq!(replay.len(), 1);
+        assert!(replay[0].starts_with("$CQSERVER:@94835:BC:BAW123:4721"), "{}", replay[0]);
+
+        update(&mut store, "#AAEGLL_TWR:SERVER:Sam Controller:1234567:password:3:100");
+        assert_eq!(store.current_owner("BAW123"), Some("EGLL_TWR"));
+        assert_eq!(store.replay_owned_by("EGLL_TWR").len(), 2);
+    }
+
+    #[test]
+    fn a_new_track_replaces_a_stale_owner() {
+        let mut store = AtcStateStore::default();
+        update(&mut store, "$CQEGLL_TWR:@94835:IT:BAW123");
+        update(&mut store, "#DAEGLL_TWR:1234567");
+        update(&mut store, "$CQEGLL_APP:@94835:IT:BAW123");
+        assert_eq!(store.current_owner("BAW123"), Some("EGLL_APP"));
+        update(&mut store, "#AAEGLL_TWR:SERVER:Sam Controller:1234567:password:3:100");
+        assert_eq!(store.current_owner("BAW123"), Some("EGLL_APP"));
+    }
+
+    #[test]
+    fn releases_aircraft_which_leave_the_traffic_picture() {
+        let mut store = AtcStateStore::default();
+        update(&mut store, "$CQEGLL_TWR:@94835:IT:BAW123");
+        update(&mut store, "$CQEGLL_TWR:@94835:IT:AI4711");
+        let present = HashSet::from([String::from("BAW123")]);
+        store.release_missing(&present);
+        assert!(store.get("AI4711").is_some());
+
+        *store.last_seen.get_mut("AI4711").unwrap() -= RELEASE_AFTER;
+        *store.last_seen.get_mut("BAW123").unwrap() -= RELEASE_AFTER;
+        store.release_missing(&present);
+        assert!(store.get("AI4711").is_none());
+        assert!(store.get("BAW123").is_some());
+        assert_eq!(store.last_seen.len(), 1);
+    }
+    #[test]
+    fn keeps_assignments_made_on_another_network() {
+        let mut store = AtcStateStore::default();
+        update(&mut store, "$CQEGLL_TWR:@94835:IT:BAW123");
+        update(&mut store, "$CQLFPG_TWR:@94835:IT:AFR456");
+        store.remove("BAW123", Network::Ivao);
+        store.remove("AFR456", Network::Vatsim);
+        assert_eq!(store.current_owner("BAW123"), Some("EGLL_TWR"));
+        assert_eq!(store.current_owner("AFR456"), Some("LFPG_TWR"));
+
+        store.remove("baw123", Network::Vatsim);
+        store.remove("AFR456", Network::Ivao);
+        assert!(store.get("BAW123").is_none());
+        assert!(store.get("AFR456").is_none());
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+
+use crate::{config::{AtisAirport, AtisConfig}, vatsim};
+
+const MAX_LINE_LENGTH: usize = 64;
+
+
+
+/// Composes text ATISes for the airports in the config, advancing each airport's information
+/// letter whenever its weather changes.
+pub struct AtisGenerator {
+    airports: HashMap<String, AtisAirport>,
+    use_vatsim_atis: bool,
+    states: HashMap<String, AtisState>,
+}
+
+struct AtisState {
+    letter: char,
+    metar: String,
+}
+
+impl AtisGenerator {
+    pub fn new(config: &AtisConfig) -> AtisGenerator {
+        AtisGenerator {
+            airports: config.airports.iter().map(|(icao, airport)| (icao.to_uppercase(), airport.clone())).collect(),
+            use_vatsim_atis: config.use_vatsim_atis,
+            states: HashMap::new(),
+        }
+    }
+
+    /// Returns the ICAO code and configuration of each airport with an ATIS.
+    pub fn airports(&self) -> impl Iterator<Item = (&String, &AtisAirport)> {
+        self.airports.iter()
+    }
+
+    /// Returns the ICAO code of the airport whose ATIS is published under `callsign`, e.g. `EGLL` for `EGLL_ATIS`.
+    pub fn airport_for_callsign(&self, callsign: &str) -> Option<&String> {
+        let icao = callsign.to_uppercase();
+        let icao = icao.strip_suffix("_ATIS")?;
+        self.airports.get_key_value(icao).map(|(icao, _)| icao)
+    }
+
+    /// Records the latest METAR for an airport. Returns the new information letter if it has changed, which
+    /// it only does when the weather has: a METAR which differs only in its observation time, as the ones
+    /// generated from the simulator's weather do every time, keeps the same letter.
+    pub fn update(&mut self, icao: &str, metar: &str) -> Option<char> {
+        match self.states.get_mut(icao) {
+            Some(state) if weather_groups(&state.metar).eq(weather_groups(metar)) => {
+                state.metar = metar.to_owned();
+                None
+            },
+            Some(state) => {
+                state.letter = next_letter(state.letter);
+                state.metar = metar.to_owned();
+                Some(state.letter)
+            },
+            None => {
+                self.states.insert(icao.to_owned(), AtisState { letter: 'A', metar: metar.to_owned() });
+                Some('A')
+            },
+        }
+    }
+
+    /// Returns the lines of the ATIS for an airport, ready to be sent in reply to an ATIS query. If enabled
+    /// and available, the text published on the network is used; otherwise the ATIS is composed from the most
+    /// recent METAR passed to [`AtisGenerator::update`].
+    pub fn atis(&self, icao: &str, vatsim_atis: Option<vatsim::Atis>) -> Option<Vec<String>> {
+        if self.use_vatsim_atis {
+            if let Some(lines) = vatsim_atis.and_then(|atis| atis.text_atis).filter(|lines| !lines.is_empty()) {
+                return Some(lines);
+            }
+        }
+
+        let airport = self.airports.get(icao)?;
+        let state = self.states.get(icao)?;
+        Some(compose(icao, airport, state.letter, &state.metar))
+    }
+}
+
+/// Returns the wind and pressure groups of a METAR, as sent in a `NEWATIS` notification.
+pub fn wind_and_pressure(metar: &str) -> (String, String) {
+    let groups = || metar.split_whitespace().skip(1);
+    let wind = groups().find(|group| group.ends_with("KT")).unwrap_or_default();
+    let pressure = groups().find(|group| group.len() == 5 && (group.starts_with('Q') || group.starts_with('A')) && group[1..].bytes().all(|b| b.is_ascii_digit())).unwrap_or_default();
+    (wind.to_owned(), pressure.to_owned())
+}
+
+/// The groups of a METAR other than its observation time.
+fn weather_groups(metar: &str) -> impl Iterator<Item = &str> {
+    metar.split_whitespace().filter(|group| !is_time(group))
+}
+
+/// Whether a METAR group is the observation time, `DDHHMMZ`.
+fn is_time(group: &str) -> bool {
+    group.len() == 7 && group.ends_with('Z') && group[..6].bytes().all(|b| b.is_ascii_digit())
+}
+
+fn next_letter(letter: char) -> char {
+    match letter {
+        'A'..='Y' => (letter as u8 + 1) as char,
+        _ => 'A',
+    }
+}
+
+/// Writes out an ATIS in the conventional order: identification, runways, weather, remarks, acknowledgement.
+fn compose(icao: &str, airport: &AtisAirport, letter: char, metar: &str) -> Vec<String> {
+    let weather = DecodedMetar::decode(metar);
+    let mut words = vec![format!("{} INFORMATION {}", icao, letter)];
+    if let Some(time) = &weather.time {
+        words.push(format!("TIME {}", time));
+    }
+
+    if !airport.approaches.is_empty() {
+        words.push(format!("EXPECT {} APPROACH", airport.approaches.join(" OR ")));
+    }
+    if !airport.arrival_runways.is_empty() {
+        words.push(format!("ARRIVAL RUNWAY {}", airport.arrival_runways.join(" AND ")));
+    }
+    if !airport.departure_runways.is_empty() {
+        words.push(format!("DEPARTURE RUNWAY {}", airport.departure_runways.join(" AND ")));
+    }
+    if let Some(transition_level) = airport.transition_level {
+        words.push(format!("TRANSITION LEVEL {}", transition_level));
+    }
+
+    if let Some(wind) = weather.wind {
+        words.push(format!("WIND {}", wind));
+    }
+    if weather.cavok {
+        words.push(String::from("CAVOK"));
+    } else {
+        if let Some(visibility) = weather.visibility {
+            words.push(format!("VISIBILITY {}", visibility));
+        }
+        if !weather.phenomena.is_empty() {
+            words.push(weather.phenomena.join(" "));
+        }
+        if !weather.clouds.is_empty() {
+            words.push(format!("CLOUD {}", weather.clouds.join(" ")));
+        }
+    }
+    if let Some((temperature, dew_point)) = weather.temperatures {
+        words.push(format!("TEMPERATURE {} DEW POINT {}", temperature, dew_point));
+    }
+    if let Some(pressure) = weather.pressure {
+        words.push(pressure);
+    }
+    words.extend(airport.remarks.iter().cloned());
+    words.push(format!("ACKNOWLEDGE RECEIPT OF INFORMATION {} AND ADVISE AIRCRAFT TYPE ON FIRST CONTACT", letter));
+
+    wrap(&words.join(" .. "), MAX_LINE_LENGTH)
+}
+
+/// Wraps text onto lines of at most `width` characters, breaking between words.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.len() + 1 + word.len() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+
+/// The parts of a METAR which an ATIS reads out, already in spoken form.
+#[derive(Debug, Default)]
+struct DecodedMetar {
+    time: Option<String>,
+    wind: Option<String>,
+    cavok: bool,
+    visibility: Option<String>,
+    phenomena: Vec<String>,
+    clouds: Vec<String>,
+    temperatures: Option<(String, String)>,
+    pressure: Option<String>,
+}
+
+impl DecodedMetar {
+    fn decode(metar: &str) -> DecodedMetar {
+        let mut decoded = DecodedMetar::default();
+        // Skip the station identifier, and stop at any trend or remarks
+        for group in metar.split_whitespace().skip(1).take_while(|group| !matches!(*group, "RMK" | "TEMPO" | "BECMG" | "NOSIG")) {
+            if is_time(group) {
+                decoded.time = Some(format!("{}Z", &group[2..6]));
+            } else if let Some(wind) = group.strip_suffix("KT") {
+                decoded.wind = Some(decode_wind(wind));
+            } else if group == "CAVOK" {
+                decoded.cavok = true;
+            } else if group.len() == 4 && group.bytes().all(|b| b.is_ascii_digit()) {
+                decoded.visibility = Some(match group {
+                    "9999" => String::from("10KM OR MORE"),
+                    _ => format!("{}M", group.parse::<u32>().unwrap_or_default()),
+                });
+            } else if let Some(visibility) = group.strip_suffix("SM") {
+                decoded.visibility = Some(format!("{} MILES", visibility));
+            } else if let Some(cloud) = decode_cloud(group) {
+                decoded.clouds.push(cloud);
+            } else if let Some((temperature, dew_point)) = group.split_once('/').filter(|(t, d)| is_temperature(t) && is_temperature(d)) {
+                decoded.temperatures = Some((decode_temperature(temperature), decode_temperature(dew_point)));
+            } else if let Some(qnh) = group.strip_prefix('Q').filter(|qnh| qnh.len() == 4 && qnh.bytes().all(|b| b.is_ascii_digit())) {
+                decoded.pressure = Some(format!("QNH {}", qnh.parse::<u32>().unwrap_or_default()));
+            } else if let Some(altimeter) = group.strip_prefix('A').filter(|alt| alt.len() == 4 && alt.bytes().all(|b| b.is_ascii_digit())) {
+                decoded.pressure = Some(format!("ALTIMETER {}.{}", &altimeter[..2], &altimeter[2..]));
+            } else if is_phenomenon(group) {
+                decoded.phenomena.push(group.to_owned());
+            }
+        }
+        decoded
+    }
+}
+
+fn decode_wind(wind: &str) -> String {
+    if wind == "00000" {
+        return String::from("CALM");
+    }
+    let (steady, gust) = match wind.split_once('G') {
+        Some((steady, gust)) => (steady, Some(gust)),
+        None => (wind, None),
+    };
+    if steady.len() < 5 {
+        return format!("{}KT", wind);
+    }
+    let (direction, speed) = steady.split_at(3);
+    let direction = if direction == "VRB" { String::from("VARIABLE") } else { format!("{} DEGREES", direction) };
+    let speed = speed.parse::<u32>().unwrap_or_default();
+    match gust.and_then(|gust| gust.parse::<u32>().ok()) {
+        Some(gust) => format!("{} {}KT GUSTING {}KT", direction, speed, gust),
+        None => format!("{} {}KT", direction, speed),
+    }
+}
+
+fn decode_cloud(group: &str) -> Option<String> {
+    match group {
+        "NSC" | "NCD" | "SKC" | "CLR" => return Some(String::from("NO SIGNIFICANT CLOUD")),
+        _ => {},
+    }
+    let coverage = ["FEW", "SCT", "BKN", "OVC", "VV"].into_iter().find(|coverage| group.starts_with(coverage))?;
+    let rest = &group[coverage.len()..];
+    let height = rest.get(..3).filter(|height| height.bytes().all(|b| b.is_ascii_digit()))?;
+    let height = height.parse::<u32>().ok()? * 100;
+    Some(format!("{} {}FT{}", coverage, height, &rest[3..]))
+}
+
+fn is_temperature(value: &str) -> bool {
+    let value = value.strip_prefix('M').unwrap_or(value);
+    !value.is_empty() && value.len() <= 2 && value.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn decode_temperature(value: &str) -> String {
+    let (minus, degrees) = match value.strip_prefix('M') {
+        Some(degrees) => (true, degrees),
+        None => (false, value),
+    };
+    let degrees = degrees.parse::<u8>().unwrap_or_default();
+    if minus && degrees != 0 {
+        format!("MINUS {}", degrees)
+    } else {
+        degrees.to_string()
+    }
+}
+
+fn is_phenomenon(group: &str) -> bool {
+    const PHENOMENA: [&str; 15] = ["DZ", "RA", "SN", "SG", "PL", "GR", "GS", "BR", "FG", "FU", "HZ", "SA", "TS", "SH", "FZ"];
+    let group = group.trim_start_matches(['-', '+']).trim_start_matches("VC");
+    group.len() >= 2 && group.len().is_multiple_of(2) && group.as_bytes().chunks(2).all(|code| PHENOMENA.iter().any(|p| p.as_bytes() == code))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator() -> AtisGenerator {
+        let airport = AtisAirport { arrival_runways: vec![String::from("27L")], departure_runways: vec![String::from("27R")], ..AtisAirport::default() };
+        AtisGenerator::new(&AtisConfig { airports: HashMap::from([(String::from("EGLL"), airport)]), use_vatsim_atis: false })
+    }
+
+    #[test]
+    fn advances_the_letter_when_the_weather_changes() {
+        let mut generator = generator();
+        assert_eq!(generator.update("EGLL", "EGLL 181020Z 25012KT 9999 FEW030 14/08 Q1016"), Some('A'));
+        assert_eq!(generator.update("EGLL", "EGLL 181020Z 25012KT 9999 FEW030 14/08 Q1016"), None);
+        assert_eq!(generator.update("EGLL", "EGLL 181050Z 25012KT 9999 FEW030 14/08 Q1015"), Some('B'));
+        assert_eq!(generator.update("EGLL", "EGLL 181120Z 25015G25KT 9999 FEW030 14/08 Q1015"), Some('C'));
+    }
+
+    #[test]
+    fn keeps_the_letter_when_only_the_time_changes() {
+        let mut generator = generator();
+        assert_eq!(generator.update("EGLL", "EGLL 181020Z 25012KT 9999 FEW030 14/08 Q1016"), Some('A'));
+        assert_eq!(generator.update("EGLL", "EGLL 181021Z 25012KT 9999 FEW030 14/08 Q1016"), None);
+        assert_eq!(generator.update("EGLL", "EGLL 181022Z 25012KT 9999 FEW030 14/08 Q1016"), None);
+        // The ATIS reads out the latest observation time
+        let atis = generator.atis("EGLL", None).unwrap().join(" ");
+        assert!(atis.starts_with("EGLL INFORMATION A .. TIME 1022Z .. ARRIVAL RUNWAY 27L"), "{}", atis);
+    }
+
+    #[test]
+    fn composes_an_atis() {
+        let mut generator = generator();
+        generator.update("EGLL", "EGLL 181020Z VRB03KT 3000 -RA BR BKN008 OVC015 M02/M04 Q0998 TEMPO 1500");
+        assert_eq!(generator.atis("EGLL", None).unwrap(), [
+            "EGLL INFORMATION A .. TIME 1020Z .. ARRIVAL RUNWAY 27L ..",
+            "DEPARTURE RUNWAY 27R .. WIND VARIABLE 3KT .. VISIBILITY 3000M ..",
+            "-RA BR .. CLOUD BKN 800FT OVC 1500FT .. TEMPERATURE MINUS 2 DEW",
+            "POINT MINUS 4 .. QNH 998 .. ACKNOWLEDGE RECEIPT OF INFORMATION A",
+            "AND ADVISE AIRCRAFT TYPE ON FIRST CONTACT",
+        ]);
+        assert_eq!(wind_and_pressure("EGLL 181020Z VRB03KT 3000 Q0998"), (String::from("VRB03KT"), String::from("Q0998")));
+    }
+}
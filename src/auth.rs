@@ -0,0 +1,193 @@
+use std::net::IpAddr;
+
+use fsd_interface::{errors::FsdError, messages::AtcRegisterMessage, AtcRating};
+
+use crate::{config::{Account, ServerConfig}, network::Network};
+
+
+
+/// What a logged-in client is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLevel {
+    Controller,
+    /// May watch the traffic and query it, but nothing it sends changes anything seen by other clients
+    Observer,
+}
+
+
+/// Checks connections and logins against the accounts and allowed networks in the config.
+pub struct Authenticator {
+    accounts: Vec<Account>,
+    allowed_networks: Vec<Cidr>,
+}
+impl Authenticator {
+    pub fn new(config: &ServerConfig) -> Result<Authenticator, String> {
+        let allowed_networks = config.allowed_networks.iter().map(|network| Cidr::parse(network)).collect::<Result<_, _>>()?;
+        Ok(Authenticator {
+            accounts: config.accounts.clone(),
+            allowed_networks,
+        })
+    }
+
+    /// Whether a client may connect from `address`. Connections from this machine are always allowed.
+    pub fn allows_address(&self, address: IpAddr) -> bool {
+        address.is_loopback() || self.allowed_networks.is_empty() || self.allowed_networks.iter().any(|network| network.contains(address))
+    }
+
+    /// Checks the CID and password of a registration. Without any accounts configured, anyone may log in
+    /// as a controller.
+    pub fn authenticate(&self, msg: &AtcRegisterMessage) -> Result<AccessLevel, FsdError> {
+        if self.accounts.is_empty() {
+            return Ok(AccessLevel::Controller);
+        }
+        let account = self.accounts.iter()
+            .find(|account| account.cid == msg.cid && account.accepts(&msg.password))
+            .ok_or(FsdError::InvalidCidPassword)?;
+
+        if !account.observer {
+            return Ok(AccessLevel::Controller);
+        }
+        match msg.rating {
+            AtcRating::Observer => Ok(AccessLevel::Observer),
+            _ => Err(FsdError::RequestedLevelTooHigh),
+        }
+    }
+
+    /// The network chosen by the account a client registered as, if any.
+    pub fn network(&self, cid: &str) -> Option<Network> {
+        self.accounts.iter().find(|account| account.cid == cid).and_then(|account| account.network)
+    }
+}
+
+impl Account {
+    fn accepts(&self, password: &str) -> bool {
+        self.password.as_deref() == Some(password) || self.tokens.iter().any(|token| token == password)
+    }
+}
+
+
+/// A network address range in CIDR notation, e.g. `192.168.1.0/24`.
+struct Cidr {
+    address: IpAddr,
+    prefix_length: u32,
+}
+impl Cidr {
+    fn parse(s: &str) -> Result<Cidr, String> {
+        let (address, prefix_length) = s.split_once('/').unwrap_or((s, ""));
+        let address: IpAddr = address.trim().parse().map_err(|_| format!("Invalid address in allowed network {s}"))?;
+        let max_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length.trim() {
+            "" => max_length,
+            length => length.parse().ok().filter(|length| *length <= max_length).ok_or(format!("Invalid prefix length in allowed network {s}"))?,
+        };
+        Ok(Cidr { address, prefix_length })
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients may appear as IPv4-mapped IPv6 addresses on a dual-stack listener
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            v4 => v4,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, address: &str) -> bool {
+        Cidr::parse(network).unwrap().contains(address.parse().unwrap())
+    }
+
+    fn register(cid: &str, password: &str, rating: u8) -> AtcRegisterMessage {
+        match fsd_interface::parse_message(format!("#AAEGLL_TWR:SERVER:Sam Controller:{cid}:{password}:{rating}:100")).unwrap() {
+            fsd_interface::FsdMessageType::AtcRegisterMessage(msg) => msg,
+            message => panic!("{:?}", message),
+        }
+    }
+
+    fn account(cid: &str, password: Option<&str>, tokens: &[&str], observer: bool) -> Account {
+        Account {
+            cid: cid.to_owned(),
+            password: password.map(str::to_owned),
+            tokens: tokens.iter().map(|token| token.to_string()).collect(),
+            observer,
+            network: None,
+        }
+    }
+
+    #[test]
+    fn matches_networks() {
+        assert!(contains("192.168.1.0/24", "192.168.1.77"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.77"));
+        assert!(!contains("192.168.1.0/24", "10.0.0.1"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(contains("192.168.1.10/32", "192.168.1.10"));
+        assert!(!contains("192.168.1.10/32", "192.168.1.11"));
+        assert!(contains("192.168.1.10", "192.168.1.10"));
+        assert!(contains("fd00::/8", "fd12:3456::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("::/0", "2001:db8::1"));
+        // IPv4 clients on a dual-stack listener, but not other IPv6 addresses, match IPv4 networks
+        assert!(contains("192.168.1.0/24", "::ffff:192.168.1.77"));
+        assert!(!contains("192.168.1.0/24", "2001:db8::1"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(!contains("::/0", "192.168.1.77"));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!(Cidr::parse("192.168.1.0/33").is_err());
+        assert!(Cidr::parse("fd00::/129").is_err());
+        assert!(Cidr::parse("192.168.1/24").is_err());
+        assert!(Cidr::parse("192.168.1.0/abc").is_err());
+    }
+
+    #[test]
+    fn allows_addresses() {
+        let config = ServerConfig { allowed_networks: vec![String::from("192.168.1.0/24")], ..ServerConfig::default() };
+        let authenticator = Authenticator::new(&config).unwrap();
+        assert!(authenticator.allows_address("192.168.1.77".parse().unwrap()));
+        assert!(!authenticator.allows_address("10.0.0.1".parse().unwrap()));
+        // This machine is always allowed
+        assert!(authenticator.allows_address("127.0.0.1".parse().unwrap()));
+        assert!(authenticator.allows_address("::1".parse().unwrap()));
+        // As is anywhere without a list
+        assert!(Authenticator::new(&ServerConfig::default()).unwrap().allows_address("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn checks_passwords() {
+        let config = ServerConfig {
+            accounts: vec![account("1234567", Some("secret"), &["machine-token"], false), account("7654321", None, &["observer-token"], true)],
+            ..ServerConfig::default()
+        };
+        let authenticator = Authenticator::new(&config).unwrap();
+        assert!(matches!(authenticator.authenticate(&register("1234567", "secret", 3)), Ok(AccessLevel::Controller)));
+        assert!(matches!(authenticator.authenticate(&register("1234567", "machine-token", 3)), Ok(AccessLevel::Controller)));
+        assert!(matches!(authenticator.authenticate(&register("1234567", "wrong", 3)), Err(FsdError::InvalidCidPassword)));
+        assert!(matches!(authenticator.authenticate(&register("1234567", "observer-token", 3)), Err(FsdError::InvalidCidPassword)));
+        assert!(matches!(authenticator.authenticate(&register("1111111", "secret", 3)), Err(FsdError::InvalidCidPassword)));
+        // An account without a password can only use its tokens
+        assert!(matches!(authenticator.authenticate(&register("7654321", "", 1)), Err(FsdError::InvalidCidPassword)));
+        assert!(matches!(authenticator.authenticate(&register("7654321", "observer-token", 1)), Ok(AccessLevel::Observer)));
+        assert!(matches!(authenticator.authenticate(&register("7654321", "observer-token", 3)), Err(FsdError::RequestedLevelTooHigh)));
+
+        // Without any accounts, anyone may log in
+        let open = Authenticator::new(&ServerConfig::default()).unwrap();
+        assert!(matches!(open.authenticate(&register("1111111", "", 3)), Ok(AccessLevel::Controller)));
+    }
+}
@@ -0,0 +1,194 @@
+use std::{borrow::Cow, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Write}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::config::CaptureConfig;
+
+/// What a password is replaced with in a capture
+const REDACTED: &str = "********";
+
+
+/// Which way a captured line went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to Traffic Viewer
+    Inbound,
+    /// From Traffic Viewer to the client
+    Outbound,
+}
+impl Direction {
+    fn marker(self) -> char {
+        match self {
+            Direction::Inbound => '<',
+            Direction::Outbound => '>',
+        }
+    }
+}
+
+
+/// Records every FSD line exchanged with one session, one per line as `<unix millis> <direction> <line>`,
+/// where the direction is `<` for lines from the client and `>` for lines to it. Passwords in `#AA`
+/// registrations are masked, both in the file and on the console.
+///
+/// Once the file reaches the configured size it's rotated: `name.log` becomes `name.1.log`, and so on, with
+/// the oldest file beyond the configured count deleted.
+pub struct ProtocolCapture {
+    path: PathBuf,
+    file: Option<File>,
+    written: u64,
+    max_file_size: u64,
+    max_files: usize,
+    console: bool,
+    name: String,
+}
+impl ProtocolCapture {
+    /// Starts a capture for a session, named after it. Returns `None` if neither capture nor console tracing
+    /// is enabled.
+    pub fn start(config: &CaptureConfig, name: &str) -> Option<ProtocolCapture> {
+        if !config.enabled && !config.console {
+            return None;
+        }
+        let path = Path::new(&config.directory).join(format!("{}-{}.log", unix_millis(), sanitise(name)));
+        let file = match config.enabled {
+            true => match fs::create_dir_all(&config.directory).and_then(|_| File::create(&path)) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    println!("Couldn't create protocol capture {}: {}", path.display(), e);
+                    None
+                },
+            },
+            false => None,
+        };
+        Some(ProtocolCapture {
+            path,
+            file,
+            written: 0,
+            max_file_size: config.max_file_size,
+            max_files: config.max_files,
+            console: config.console,
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, line: &str) {
+        let line = redact(line);
+        if self.console {
+            match direction {
+                Direction::Inbound => println!("RECV {}: {}", self.name, line),
+                Direction::Outbound => println!("SEND {}: {}", self.name, line),
+            }
+        }
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let entry = format!("{} {} {}\n", unix_millis(), direction.marker(), line);
+        if file.write_all(entry.as_bytes()).is_err() {
+            self.file = None;
+            return;
+        }
+        self.written += entry.len() as u64;
+        if self.max_file_size > 0 && self.written >= self.max_file_size {
+            self.rotate();
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.file = None;
+        for index in (1..self.max_files.max(1)).rev() {
+            let from = if index == 1 { self.path.clone() } else { self.rotated_path(index - 1) };
+            fs::rename(from, self.rotated_path(index)).ok();
+        }
+        if self.max_files <= 1 {
+            fs::remove_file(&self.path).ok();
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path).ok();
+        self.written = 0;
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        self.path.with_extension(format!("{}.log", index))
+    }
+}
+
+
+/// One line read back from a capture file.
+#[derive(Debug, Clone)]
+pub struct CapturedLine {
+    pub direction: Direction,
+    pub line: String,
+}
+
+/// Reads a capture file written by [`ProtocolCapture`]. Lines in any other format are skipped.
+pub fn read(path: &Path) -> std::io::Result<Vec<CapturedLine>> {
+    let mut lines = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut parts = line.splitn(3, ' ');
+        let (Some(millis), Some(direction), Some(text)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        let direction = match direction {
+            "<" => Direction::Inbound,
+            ">" => Direction::Outbound,
+            _ => continue,
+        };
+        if millis.parse::<u64>().is_ok() {
+            lines.push(CapturedLine { direction, line: text.to_owned() });
+        }
+    }
+    Ok(lines)
+}
+
+/// Masks the password in an `#AA` registration, i.e. `#AAcallsign:SERVER:name:cid:password:rating:protocol`.
+fn redact(line: &str) -> Cow<'_, str> {
+    if !line.starts_with("#AA") {
+        return Cow::Borrowed(line);
+    }
+    let mut fields: Vec<&str> = line.split(':').collect();
+    match fields.get_mut(4) {
+        Some(password) if !password.is_empty() => {
+            *password = REDACTED;
+            Cow::Owned(fields.join(":"))
+        },
+        _ => Cow::Borrowed(line),
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_millis() as u64).unwrap_or_default()
+}
+
+fn sanitise(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_registration_passwords() {
+        assert_eq!(redact("#AAEGLL_TWR:SERVER:Sam Controller:1234567:hunter2:3:100"), "#AAEGLL_TWR:SERVER:Sam Controller:1234567:********:3:100");
+        assert_eq!(redact("#AAEGLL_TWR:SERVER:Sam Controller:1234567::3:100"), "#AAEGLL_TWR:SERVER:Sam Controller:1234567::3:100");
+        assert_eq!(redact("#AAEGLL_TWR:SERVER"), "#AAEGLL_TWR:SERVER");
+        assert_eq!(redact("#TMEGLL_TWR:BAW1:a:b:hunter2"), "#TMEGLL_TWR:BAW1:a:b:hunter2");
+    }
+
+    #[test]
+    fn captures_without_passwords() {
+        let directory = std::env::temp_dir().join(format!("traffic-viewer-capture-{}", unix_millis()));
+        let config = CaptureConfig { enabled: true, directory: directory.to_string_lossy().into_owned(), ..CaptureConfig::default() };
+        let mut capture = ProtocolCapture::start(&config, "EGLL_TWR").unwrap();
+        capture.record(Direction::Inbound, "#AAEGLL_TWR:SERVER:Sam Controller:1234567:hunter2:3:100");
+        capture.record(Direction::Outbound, "#TMSERVER:EGLL_TWR:Welcome");
+        let path = capture.path.clone();
+        drop(capture);
+
+        let lines = read(&path).unwrap();
+        fs::remove_dir_all(&directory).ok();
+        let lines: Vec<(Direction, &str)> = lines.iter().map(|captured| (captured.direction, captured.line.as_str())).collect();
+        assert_eq!(lines, [
+            (Direction::Inbound, "#AAEGLL_TWR:SERVER:Sam Controller:1234567:********:3:100"),
+            (Direction::Outbound, "#TMSERVER:EGLL_TWR:Welcome"),
+        ]);
+    }
+}
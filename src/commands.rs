@@ -0,0 +1,152 @@
+/// A command sent to Traffic Viewer as a private text message to `SERVER`, e.g. `.filter range 80`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    /// Worker health, sessions and traffic counts
+    Status,
+    /// The traffic currently being sent, with where each target came from
+    List,
+    MetarRefresh,
+    Filter(FilterCommand),
+    /// Matches a simulator callsign to a network one, or clears the match if there's no network callsign
+    Match { sim: String, network: Option<String> },
+    /// Summarises an aircraft's flight plan
+    FlightPlan(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterCommand {
+    Show,
+    /// How far from the client network pilots are forwarded, in nautical miles
+    Range(f64),
+    /// Whether network pilots the simulator hasn't injected are forwarded at all
+    Network(bool),
+}
+
+impl Command {
+    /// Whether the command changes anything, which observers aren't allowed to do.
+    pub fn is_mutating(&self) -> bool {
+        matches!(self, Command::MetarRefresh | Command::Filter(FilterCommand::Range(_) | FilterCommand::Network(_)) | Command::Match { .. })
+    }
+}
+
+/// The replies to `.help`.
+pub const HELP: [&str; 7] = [
+    ".status - worker, session and traffic status",
+    ".list - the traffic being sent and where it's from, up to 20 aircraft",
+    ".metar refresh - download the METARs again",
+    ".filter [range NM | network on/off] - show or change the network traffic filter",
+    ".match SIM=NET - match a simulator callsign to a network one",
+    ".match SIM= - clear a match made with .match",
+    ".fp CALLSIGN - summarise a flight plan",
+];
+
+
+/// Parses a text message. Text which doesn't start with `.` isn't a command, and gives `None`; a command
+/// which can't be understood gives a message explaining its usage.
+pub fn parse(text: &str) -> Option<Result<Command, String>> {
+    let text = text.trim().strip_prefix('.')?;
+    let mut words = text.split_whitespace();
+    let name = words.next().unwrap_or_default().to_lowercase();
+    let arguments: Vec<&str> = words.collect();
+    let command = match (name.as_str(), arguments.as_slice()) {
+        ("help", _) => Ok(Command::Help),
+        ("status", []) => Ok(Command::Status),
+        ("list", []) => Ok(Command::List),
+        ("metar", [refresh]) if refresh.eq_ignore_ascii_case("refresh") => Ok(Command::MetarRefresh),
+        ("metar", _) => Err("Usage .metar refresh"),
+        ("filter", []) => Ok(Command::Filter(FilterCommand::Show)),
+        ("filter", [setting, value]) if setting.eq_ignore_ascii_case("range") => match value.parse::<f64>() {
+            Ok(range) if range.is_finite() && range > 0.0 => Ok(Command::Filter(FilterCommand::Range(range))),
+            _ => Err("The range must be a positive number of nautical miles"),
+        },
+        ("filter", [setting, value]) if setting.eq_ignore_ascii_case("network") => match value.to_lowercase().as_str() {
+            "on" => Ok(Command::Filter(FilterCommand::Network(true))),
+            "off" => Ok(Command::Filter(FilterCommand::Network(false))),
+            _ => Err("Usage .filter network on/off"),
+        },
+        ("filter", _) => Err("Usage .filter [range NM | network on/off]"),
+        ("match", [pair]) => match pair.split_once('=') {
+            Some((sim, network)) if !sim.is_empty() => Ok(Command::Match {
+                sim: sim.to_uppercase(),
+                network: Some(network.to_uppercase()).filter(|network| !network.is_empty()),
+            }),
+            _ => Err("Usage .match SIM=NET"),
+        },
+        ("match", _) => Err("Usage .match SIM=NET"),
+        ("fp", [callsign]) => Ok(Command::FlightPlan(callsign.to_uppercase())),
+        ("fp", _) => Err("Usage .fp CALLSIGN"),
+        ("status" | "list", _) => Err("That command doesn't take any arguments"),
+        _ => Err("Unknown command, send .help for a list"),
+    };
+    Some(command.map_err(str::to_owned))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str) -> Command {
+        parse(text).unwrap().unwrap()
+    }
+
+    fn usage(text: &str) -> String {
+        parse(text).unwrap().unwrap_err()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(command(".help"), Command::Help);
+        assert_eq!(command(".HELP me"), Command::Help);
+        assert_eq!(command(" .status "), Command::Status);
+        assert_eq!(command(".list"), Command::List);
+        assert_eq!(command(".metar Refresh"), Command::MetarRefresh);
+        assert_eq!(command(".filter"), Command::Filter(FilterCommand::Show));
+        assert_eq!(command(".filter range 80"), Command::Filter(FilterCommand::Range(80.0)));
+        assert_eq!(command(".filter RANGE 12.5"), Command::Filter(FilterCommand::Range(12.5)));
+        assert_eq!(command(".filter network on"), Command::Filter(FilterCommand::Network(true)));
+        assert_eq!(command(".filter network OFF"), Command::Filter(FilterCommand::Network(false)));
+        assert_eq!(command(".match afr12=af12"), Command::Match { sim: String::from("AFR12"), network: Some(String::from("AF12")) });
+        assert_eq!(command(".match AFR12="), Command::Match { sim: String::from("AFR12"), network: None });
+        assert_eq!(command(".fp baw123"), Command::FlightPlan(String::from("BAW123")));
+    }
+
+    #[test]
+    fn ignores_text_which_isnt_a_command() {
+        assert_eq!(parse("Hello"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("status."), None);
+    }
+
+    #[test]
+    fn explains_usage() {
+        assert_eq!(usage(".frobnicate"), "Unknown command, send .help for a list");
+        assert_eq!(usage("."), "Unknown command, send .help for a list");
+        assert_eq!(usage(".status now"), "That command doesn't take any arguments");
+        assert_eq!(usage(".list all"), "That command doesn't take any arguments");
+        assert_eq!(usage(".metar"), "Usage .metar refresh");
+        assert_eq!(usage(".metar EGLL"), "Usage .metar refresh");
+        assert_eq!(usage(".filter range"), "Usage .filter [range NM | network on/off]");
+        assert_eq!(usage(".filter range far"), "The range must be a positive number of nautical miles");
+        assert_eq!(usage(".filter range -5"), "The range must be a positive number of nautical miles");
+        assert_eq!(usage(".filter range inf"), "The range must be a positive number of nautical miles");
+        assert_eq!(usage(".filter network maybe"), "Usage .filter network on/off");
+        assert_eq!(usage(".filter colour red"), "Usage .filter [range NM | network on/off]");
+        assert_eq!(usage(".match AFR12"), "Usage .match SIM=NET");
+        assert_eq!(usage(".match =AF12"), "Usage .match SIM=NET");
+        assert_eq!(usage(".match AFR12 AF12"), "Usage .match SIM=NET");
+        assert_eq!(usage(".fp"), "Usage .fp CALLSIGN");
+        assert_eq!(usage(".fp BAW1 BAW2"), "Usage .fp CALLSIGN");
+    }
+
+    #[test]
+    fn only_settings_changes_are_mutating() {
+        for text in [".metar refresh", ".filter range 80", ".filter network off", ".match AFR12=AF12", ".match AFR12="] {
+            assert!(command(text).is_mutating(), "{}", text);
+        }
+        for text in [".help", ".status", ".list", ".filter", ".fp BAW123"] {
+            assert!(!command(text).is_mutating(), "{}", text);
+        }
+    }
+}
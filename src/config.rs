@@ -0,0 +1,250 @@
+use std::{collections::HashMap, fs, io::ErrorKind};
+
+use serde::Deserialize;
+
+use crate::{encoding::Encoding, network::Network, weather::MetarSource};
+
+const CONFIG_FILE_PATH: &str = "traffic-viewer.json";
+
+
+
+/// User configuration, read from `traffic-viewer.json` in the working directory.
+///
+/// Every field has a default, so the file (and any section within it) is optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub capture: CaptureConfig,
+    pub network: NetworkConfig,
+    pub api: ApiConfig,
+    pub metar: MetarConfig,
+    pub weather: WeatherConfig,
+    pub atis: AtisConfig,
+    pub controllers: ControllersConfig,
+    pub matcher: MatcherConfig,
+    pub traffic: TrafficConfig,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
+        match fs::read_to_string(CONFIG_FILE_PATH) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(error) => Err(Box::new(error)),
+        }
+    }
+}
+
+
+/// Settings for the FSD server which ATC clients connect to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    /// Accounts which may log in. When there are none, any client may log in without a password
+    pub accounts: Vec<Account>,
+    /// Networks which remote clients may connect from, e.g. `"192.168.1.0/24"`. When there are none, any
+    /// address may connect. Connections from this machine are always allowed
+    pub allowed_networks: Vec<String>,
+    /// How many packets may wait to be written to each client before the oldest are dropped
+    pub send_queue_capacity: usize,
+    /// The most packets written to each client per second, or 0 for no limit
+    pub max_packets_per_second: u32,
+    /// The character encoding used with clients: `auto`, `utf8`, `windows1252` or `iso_8859_1`
+    pub encoding: Encoding,
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: String::from("127.0.0.1:6809"),
+            accounts: vec![],
+            allowed_networks: vec![],
+            send_queue_capacity: 2000,
+            max_packets_per_second: 500,
+            encoding: Encoding::default(),
+        }
+    }
+}
+
+/// Settings for tracing and capturing the FSD traffic with each client, see [`crate::capture::ProtocolCapture`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Whether to write each session's lines to a file
+    pub enabled: bool,
+    /// Whether to print each session's lines, sent and received, to the console
+    pub console: bool,
+    pub directory: String,
+    /// The size in bytes at which a capture file is rotated, or 0 to never rotate
+    pub max_file_size: u64,
+    /// How many files to keep for each session, including the current one
+    pub max_files: usize,
+}
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            enabled: false,
+            console: false,
+            directory: String::from("captures"),
+            max_file_size: 5_000_000,
+            max_files: 5,
+        }
+    }
+}
+
+/// A login for the FSD server, matched against the CID and password the client registers with.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Account {
+    pub cid: String,
+    pub password: Option<String>,
+    /// Tokens which are accepted in place of the password, e.g. one per machine
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// Observer accounts may only log in with the observer rating and can't change anything other clients see
+    #[serde(default)]
+    pub observer: bool,
+    /// The network whose data this account's sessions are given, in place of `network.provider`
+    #[serde(default)]
+    pub network: Option<Network>,
+}
+
+
+/// Settings for the online network which pilot and controller data is taken from.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// The network used by sessions whose account doesn't choose one
+    pub provider: Network,
+    /// Address to download the network's data feed from, in place of the provider's own
+    pub data_url: Option<String>,
+}
+
+/// Settings for the local HTTP / WebSocket API.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub bind_address: String,
+}
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            enabled: true,
+            bind_address: String::from("127.0.0.1:8080"),
+        }
+    }
+}
+
+
+/// Settings for METAR handling.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MetarConfig {
+    /// METARs to serve in place of the live ones, keyed by ICAO code. Useful for training weather.
+    pub overrides: HashMap<String, String>,
+    /// Address of a `metar.php` style service to download METARs from, in place of `metar.vatsim.net`
+    pub url: Option<String>,
+}
+
+
+/// Settings for the simulator weather reader.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WeatherConfig {
+    pub metar_source: MetarSource,
+}
+
+
+/// Settings for the ATIS generator.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AtisConfig {
+    /// Airports to publish an ATIS for, keyed by ICAO code
+    pub airports: HashMap<String, AtisAirport>,
+    /// Whether to use the text of the real ATIS from the network, where there is one online
+    pub use_vatsim_atis: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AtisAirport {
+    pub frequency: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub arrival_runways: Vec<String>,
+    pub departure_runways: Vec<String>,
+    pub approaches: Vec<String>,
+    pub transition_level: Option<u32>,
+    pub remarks: Vec<String>,
+}
+impl Default for AtisAirport {
+    fn default() -> Self {
+        AtisAirport {
+            frequency: String::from("199.998"),
+            latitude: 0.0,
+            longitude: 0.0,
+            arrival_runways: vec![],
+            departure_runways: vec![],
+            approaches: vec![],
+            transition_level: None,
+            remarks: vec![],
+        }
+    }
+}
+
+
+/// Settings for mirroring the network's controllers into the local session.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ControllersConfig {
+    pub mirror: bool,
+}
+impl Default for ControllersConfig {
+    fn default() -> Self {
+        ControllersConfig {
+            mirror: true,
+        }
+    }
+}
+
+
+/// Settings for matching simulator AI callsigns to network pilots, see [`crate::matcher::CallsignMatcher`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MatcherConfig {
+    /// Simulator callsign to network callsign. A key ending in `*` replaces a prefix, e.g. `"EZS*": "EZY*"`
+    pub aliases: HashMap<String, String>,
+    /// Additional IATA to ICAO airline designators
+    pub airlines: HashMap<String, String>,
+    /// Path of the manual override file
+    pub override_file: String,
+}
+impl Default for MatcherConfig {
+    fn default() -> Self {
+        MatcherConfig {
+            aliases: HashMap::new(),
+            airlines: HashMap::new(),
+            override_file: String::from("callsign-overrides.txt"),
+        }
+    }
+}
+
+
+/// Settings for the traffic picture sent to the ATC client.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TrafficConfig {
+    /// Whether to also send network pilots which the simulator hasn't injected, using the positions in the network feed
+    pub forward_network_pilots: bool,
+    /// How far from the ATC client's position network pilots are forwarded, in nautical miles
+    pub network_pilot_range: f64,
+}
+impl Default for TrafficConfig {
+    fn default() -> Self {
+        TrafficConfig {
+            forward_network_pilots: false,
+            network_pilot_range: 300.0,
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use fsd_interface::{messages::{AtcDeregisterMessage, AtcPositionUpdateMessage, AtcRegisterMessage}, AtcRating, AtcType, ProtocolRevision, RadioFrequency};
+
+use crate::vatsim::Controller;
+
+const OBSERVER_FACILITY: u8 = 0;
+
+
+
+/// Presents the controllers online on the network to the local ATC client as though they were connected
+/// to the same FSD server, announcing each as it logs on and withdrawing it as it logs off.
+#[derive(Default)]
+pub struct ControllerMirror {
+    announced: HashMap<String, Controller>,
+}
+
+impl ControllerMirror {
+    /// Brings the mirrored controllers in line with `online`, returning the packets to send to the
+    /// clients. `exclude` holds the local clients' own callsigns, which must never be mirrored back to them.
+    ///
+    /// The network feeds don't give controller positions, so each controller is placed at `centre`
+    /// (normally the client's own position) to keep it inside the client's range.
+    pub fn update(&mut self, online: Vec<Controller>, exclude: &[String], centre: (f64, f64)) -> Vec<String> {
+        let mut packets = vec![];
+        let online: HashMap<String, Controller> = online.into_iter()
+            .filter(|controller| controller.facility != OBSERVER_FACILITY && !exclude.contains(&controller.callsign))
+            .map(|controller| (controller.callsign.clone(), controller))
+            .collect();
+
+        for (callsign, controller) in self.announced.iter() {
+            if !online.contains_key(callsign) {
+                packets.push(AtcDeregisterMessage::new(callsign, controller.cid.to_string()).to_string());
+            }
+        }
+        for (callsign, controller) in online.iter() {
+            if !self.announced.contains_key(callsign) {
+                packets.push(AtcRegisterMessage::new(callsign, "SERVER", &controller.name, controller.cid.to_string(), "", rating(controller), ProtocolRevision::Classic).to_string());
+            }
+            let frequencies: Vec<RadioFrequency> = RadioFrequency::try_from_human_readable_string(&controller.frequency).into_iter().collect();
+            let atc_type = controller.facility.to_string().parse().unwrap_or(AtcType::Observer);
+            packets.push(AtcPositionUpdateMessage::new(callsign, frequencies, atc_type, controller.visual_range, rating(controller), centre.0, centre.1, 0).to_string());
+        }
+
+        self.announced = online;
+        packets
+    }
+
+    /// The registration packets for every controller currently mirrored, for a client which logs in
+    /// after they were first announced.
+    pub fn announcements(&self) -> Vec<String> {
+        self.announced.iter()
+            .map(|(callsign, controller)| AtcRegisterMessage::new(callsign, "SERVER", &controller.name, controller.cid.to_string(), "", rating(controller), ProtocolRevision::Classic).to_string())
+            .collect()
+    }
+
+    pub fn get(&self, callsign: &str) -> Option<&Controller> {
+        self.announced.get(&callsign.to_uppercase())
+    }
+}
+
+fn rating(controller: &Controller) -> AtcRating {
+    controller.rating.to_string().parse().unwrap_or(AtcRating::Observer)
+}
@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+
+
+
+/// The character encoding used on the wire to an ATC client.
+///
+/// FSD itself doesn't specify one. Clients on Windows have traditionally used the system code page,
+/// which for most users is Windows-1252, while newer clients send UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// Windows-1252 until the client sends something which isn't plain ASCII, and from then on whichever
+    /// of UTF-8 and Windows-1252 that was, until it reconnects
+    #[default]
+    Auto,
+    Utf8,
+    Windows1252,
+    #[serde(rename = "iso_8859_1")]
+    Latin1,
+}
+
+
+/// Converts one session's packets between text and bytes. Characters which can't be represented in the
+/// session's encoding are transliterated, and bytes which aren't valid in it are decoded as best we can,
+/// so that no line is ever dropped for its encoding.
+#[derive(Debug, Clone)]
+pub struct Codec {
+    encoding: Encoding,
+}
+impl Codec {
+    pub fn new(encoding: Encoding) -> Codec {
+        Codec {
+            encoding,
+        }
+    }
+
+    /// The encoding in use, which for [`Encoding::Auto`] is decided by the first non-ASCII line received.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Decodes a line from the client. With [`Encoding::Auto`], the first line which isn't plain ASCII
+    /// settles the encoding for the rest of the session, so lines sent before then will have been
+    /// encoded as Windows-1252 even if the client turns out to use UTF-8. The decision is made once only,
+    /// and isn't revisited however later lines look.
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        if self.encoding == Encoding::Auto && !bytes.is_ascii() {
+            self.encoding = match std::str::from_utf8(bytes) {
+                Ok(_) => Encoding::Utf8,
+                Err(_) => Encoding::Windows1252,
+            };
+            println!("Client appears to use {:?}", self.encoding);
+        }
+        match self.encoding {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Auto | Encoding::Windows1252 => bytes.iter().map(|b| decode_windows_1252(*b)).collect(),
+            Encoding::Latin1 => bytes.iter().map(|b| *b as char).collect(),
+        }
+    }
+
+    pub fn encode(&self, string: &str) -> Vec<u8> {
+        let encode_char = match self.encoding {
+            Encoding::Utf8 => return string.as_bytes().to_vec(),
+            Encoding::Auto | Encoding::Windows1252 => encode_windows_1252,
+            Encoding::Latin1 => encode_latin1,
+        };
+        let mut bytes = Vec::with_capacity(string.len());
+        for c in string.chars() {
+            match encode_char(c) {
+                Some(b) => bytes.push(b),
+                None => bytes.extend(transliterate(c).chars().map(|c| encode_char(c).unwrap_or(b'?'))),
+            }
+        }
+        bytes
+    }
+}
+
+
+/// The characters Windows-1252 has in place of the C1 controls. The five unassigned bytes decode to the
+/// C1 control of the same value, as browsers do, so that they survive a round trip.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+fn decode_windows_1252(b: u8) -> char {
+    match b {
+        0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+        _ => b as char,
+    }
+}
+
+fn encode_windows_1252(c: char) -> Option<u8> {
+    match c as u32 {
+        0x00..=0x7F | 0xA0..=0xFF => Some(c as u8),
+        _ => WINDOWS_1252_HIGH.iter().position(|high| *high == c).map(|index| 0x80 + index as u8),
+    }
+}
+
+fn encode_latin1(c: char) -> Option<u8> {
+    u8::try_from(c as u32).ok()
+}
+
+
+/// Latin Extended-A, U+0100 to U+017F, without diacritics.
+const LATIN_EXTENDED_A: [&str; 128] = [
+    "A", "a", "A", "a", "A", "a", "C", "c", "C", "c", "C", "c", "C", "c", "D", "d",
+    "D", "d", "E", "e", "E", "e", "E", "e", "E", "e", "E", "e", "G", "g", "G", "g",
+    "G", "g", "G", "g", "H", "h", "H", "h", "I", "i", "I", "i", "I", "i", "I", "i",
+    "I", "i", "IJ", "ij", "J", "j", "K", "k", "k", "L", "l", "L", "l", "L", "l", "L",
+    "l", "L", "l", "N", "n", "N", "n", "N", "n", "n", "N", "n", "O", "o", "O", "o",
+    "O", "o", "OE", "oe", "R", "r", "R", "r", "R", "r", "S", "s", "S", "s", "S", "s",
+    "S", "s", "T", "t", "T", "t", "T", "t", "U", "u", "U", "u", "U", "u", "U", "u",
+    "U", "u", "U", "u", "W", "w", "Y", "y", "Y", "Z", "z", "Z", "z", "Z", "z", "s",
+];
+
+/// The Russian alphabet, U+0410 to U+042F, in Latin letters.
+const CYRILLIC: [&str; 32] = [
+    "A", "B", "V", "G", "D", "E", "ZH", "Z", "I", "Y", "K", "L", "M", "N", "O", "P",
+    "R", "S", "T", "U", "F", "KH", "TS", "CH", "SH", "SHCH", "", "Y", "", "E", "YU", "YA",
+];
+
+/// An ASCII stand-in for a character, or `?` if there's no sensible one.
+fn transliterate(c: char) -> String {
+    let code = c as u32;
+    let ascii = match c {
+        // Punctuation which Windows-1252 has and ISO-8859-1 doesn't, or which comes from elsewhere
+        '‘' | '’' | '‚' | '′' => "'", '“' | '”' | '„' | '″' => "\"", '–' | '—' | '‐' | '−' => "-", '…' => "...",
+        '•' => "*", '€' => "EUR", '™' => "TM", '‰' => "o/oo", '‹' => "<", '›' => ">", 'ˆ' => "^", '˜' => "~",
+        '†' | '‡' => "+", 'ƒ' => "f", '\u{A0}' | '\u{2002}'..='\u{200A}' => " ",
+        // Combining diacritics, from decomposed text, are simply left off their letter
+        '\u{300}'..='\u{36F}' => "",
+        _ => match code {
+            0x100..=0x17F => LATIN_EXTENDED_A[(code - 0x100) as usize],
+            0x410..=0x42F => CYRILLIC[(code - 0x410) as usize],
+            0x430..=0x44F => return CYRILLIC[(code - 0x430) as usize].to_lowercase(),
+            0x401 => "E",
+            0x451 => "e",
+            0x404 => "YE",
+            0x454 => "ye",
+            0x406 => "I",
+            0x456 => "i",
+            0x407 => "YI",
+            0x457 => "yi",
+            0x490 => "G",
+            0x491 => "g",
+            _ => "?",
+        },
+    };
+    ascii.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 4] = ["Müller", "Łódź", "Москва", "東京"];
+
+    #[test]
+    fn every_byte_survives_a_round_trip() {
+        for encoding in [Encoding::Windows1252, Encoding::Latin1] {
+            let mut codec = Codec::new(encoding);
+            for b in 0x00..=0xFF {
+                let decoded = codec.decode(&[b]);
+                assert_eq!(decoded.chars().count(), 1, "{:?} {:#04X}", encoding, b);
+                assert_eq!(codec.encode(&decoded), [b], "{:?} {:#04X}", encoding, b);
+            }
+        }
+        assert_eq!(Codec::new(Encoding::Windows1252).decode(&[0x80, 0x93, 0x94, 0x9F]), "€“”Ÿ");
+        assert_eq!(Codec::new(Encoding::Latin1).decode(&[0x80, 0xE9]), "\u{80}é");
+    }
+
+    #[test]
+    fn encodes_names_in_each_encoding() {
+        let encode = |encoding| NAMES.map(|name| Codec::new(encoding).encode(name));
+        assert_eq!(encode(Encoding::Utf8), NAMES.map(|name| name.as_bytes().to_vec()));
+        for encoding in [Encoding::Auto, Encoding::Windows1252, Encoding::Latin1] {
+            let encoded = encode(encoding);
+            assert_eq!(encoded[0], b"M\xFCller", "{:?}", encoding);
+            assert_eq!(encoded[1], b"L\xF3dz", "{:?}", encoding);
+            assert_eq!(encoded[2], b"Moskva", "{:?}", encoding);
+            assert_eq!(encoded[3], b"??", "{:?}", encoding);
+            assert_eq!(Codec::new(encoding).decode(&encoded[0]), "Müller");
+        }
+        // Windows-1252 has the typographic punctuation which ISO-8859-1 lacks
+        assert_eq!(Codec::new(Encoding::Windows1252).encode("“Tower” – 120€"), b"\x93Tower\x94 \x96 120\x80");
+        assert_eq!(Codec::new(Encoding::Latin1).encode("“Tower” – 120€"), b"\"Tower\" - 120EUR");
+    }
+
+    #[test]
+    fn decodes_names_in_each_encoding() {
+        for name in NAMES {
+            assert_eq!(Codec::new(Encoding::Utf8).decode(name.as_bytes()), name);
+        }
+        assert_eq!(Codec::new(Encoding::Windows1252).decode(b"M\xFCller \x8Ckonomie"), "Müller Œkonomie");
+        assert_eq!(Codec::new(Encoding::Latin1).decode(b"M\xFCller"), "Müller");
+    }
+
+    #[test]
+    fn detects_utf8() {
+        let mut codec = Codec::new(Encoding::Auto);
+        assert_eq!(codec.decode(b"#TMEGLL_TWR:BAW1:hello"), "#TMEGLL_TWR:BAW1:hello");
+        assert_eq!(codec.encoding(), Encoding::Auto);
+        assert_eq!(codec.decode("#TMEGLL_TWR:BAW1:Grüß Gott, Łódź".as_bytes()), "#TMEGLL_TWR:BAW1:Grüß Gott, Łódź");
+        assert_eq!(codec.encoding(), Encoding::Utf8);
+        assert_eq!(codec.encode("Müller"), "Müller".as_bytes());
+        // The decision stands, so a stray Windows-1252 line is decoded lossily rather than switching back
+        assert_eq!(codec.decode(b"M\xFCller"), "M\u{FFFD}ller");
+        assert_eq!(codec.encoding(), Encoding::Utf8);
+    }
+
+    #[test]
+    fn detects_windows_1252() {
+        let mut codec = Codec::new(Encoding::Auto);
+        assert_eq!(codec.encode("Müller"), b"M\xFCller");
+        assert_eq!(codec.decode(b"#TMEGLL_TWR:BAW1:M\xFCller"), "#TMEGLL_TWR:BAW1:Müller");
+        assert_eq!(codec.encoding(), Encoding::Windows1252);
+        // Nor does a later UTF-8 line switch it
+        assert_eq!(codec.decode("ü".as_bytes()), "Ã¼");
+        assert_eq!(codec.encoding(), Encoding::Windows1252);
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_lossily() {
+        let mut codec = Codec::new(Encoding::Utf8);
+        assert_eq!(codec.decode(b"EGLL \xC3 \xFF\xFE ok"), "EGLL \u{FFFD} \u{FFFD}\u{FFFD} ok");
+        assert_eq!(codec.decode(b"caf\xC3\xA9"), "café");
+    }
+}
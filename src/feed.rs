@@ -0,0 +1,228 @@
+use serde::Serialize;
+
+use crate::{aircraft::Aircraft, api::LocalController, vatsim::{Facility, FlightPlan}, worker::{unix_timestamp, WorkerHandle}};
+
+const SERVER_IDENT: &str = "TRAFFIC-VIEWER";
+const INHG_TO_MB: f32 = 33.8639;
+
+
+
+/// A `vatsim-data.json` v3 document describing everything Traffic Viewer can currently see.
+///
+/// Pilots come from the simulator traffic picture, enriched with whatever the worker holds for the
+/// same callsign; controllers are the ATC clients connected to Traffic Viewer.
+#[derive(Serialize)]
+pub struct Feed<'a> {
+    general: General,
+    pilots: Vec<Pilot<'a>>,
+    controllers: Vec<Controller<'a>>,
+    atis: Vec<Controller<'a>>,
+    servers: Vec<Server>,
+    prefiles: Vec<()>,
+    facilities: Vec<Facility>,
+    ratings: Vec<()>,
+    pilot_ratings: Vec<()>,
+    military_ratings: Vec<()>,
+}
+
+impl<'a> Feed<'a> {
+    pub fn render(traffic: &'a [(&'a Aircraft, u64)], own_aircraft: Option<&'a Aircraft>, controllers: &'a [&'a LocalController], worker: &WorkerHandle) -> Feed<'a> {
+        let now = unix_timestamp();
+        let mut pilots: Vec<Pilot> = traffic.iter().map(|(aircraft, logon_time)| Pilot::new(aircraft, *logon_time, now, worker)).collect();
+        if let Some(own_aircraft) = own_aircraft {
+            pilots.push(Pilot::new(own_aircraft, now, now, worker));
+        }
+
+        let (atis, controllers): (Vec<Controller>, Vec<Controller>) = controllers.iter()
+            .map(|controller| Controller::new(controller, now))
+            .partition(|controller| controller.callsign.ends_with("_ATIS"));
+        // The network's own table if it has one, so that its facility numbers keep their meaning
+        let mut facilities = worker.facilities();
+        if facilities.is_empty() {
+            facilities = FACILITIES.iter().map(|(id, short, long)| Facility { id: *id, short: short.to_string(), long: long.to_string() }).collect();
+        }
+
+        Feed {
+            general: General {
+                version: 3,
+                reload: 1,
+                update: compact_timestamp(now),
+                update_timestamp: iso8601(now),
+                connected_clients: pilots.len() + controllers.len() + atis.len(),
+                unique_users: pilots.len() + controllers.len() + atis.len(),
+            },
+            pilots,
+            controllers,
+            atis,
+            servers: vec![Server {
+                ident: SERVER_IDENT,
+                hostname_or_ip: "127.0.0.1",
+                location: "Local",
+                name: SERVER_IDENT,
+                client_connections_allowed: true,
+                is_sweatbox: false,
+            }],
+            prefiles: vec![],
+            facilities,
+            ratings: vec![],
+            pilot_ratings: vec![],
+            military_ratings: vec![],
+        }
+    }
+}
+
+
+#[derive(Serialize)]
+struct General {
+    version: u8,
+    reload: u8,
+    update: String,
+    update_timestamp: String,
+    connected_clients: usize,
+    unique_users: usize,
+}
+
+#[derive(Serialize)]
+struct Pilot<'a> {
+    cid: i32,
+    name: String,
+    callsign: &'a str,
+    server: &'static str,
+    pilot_rating: u8,
+    military_rating: u8,
+    latitude: f64,
+    longitude: f64,
+    altitude: i32,
+    groundspeed: u32,
+    transponder: &'a str,
+    heading: u32,
+    qnh_i_hg: f32,
+    qnh_mb: i32,
+    flight_plan: Option<FlightPlan>,
+    logon_time: String,
+    last_updated: String,
+}
+impl<'a> Pilot<'a> {
+    fn new(aircraft: &'a Aircraft, logon_time: u64, now: u64, worker: &WorkerHandle) -> Pilot<'a> {
+        let details = worker.details(&aircraft.callsign);
+        let qnh_i_hg = details.as_ref().map(|details| details.qnh_i_hg).unwrap_or(29.92);
+        Pilot {
+            cid: details.as_ref().map(|details| details.cid).unwrap_or_default(),
+            name: details.as_ref().map(|details| details.name.clone()).unwrap_or_default(),
+            callsign: &aircraft.callsign,
+            server: SERVER_IDENT,
+            pilot_rating: 0,
+            military_rating: 0,
+            latitude: aircraft.lat,
+            longitude: aircraft.lon,
+            altitude: aircraft.alt.round() as i32,
+            groundspeed: aircraft.gs,
+            transponder: &aircraft.transponder,
+            heading: aircraft.hdg.round() as u32 % 360,
+            qnh_i_hg,
+            qnh_mb: (qnh_i_hg * INHG_TO_MB).round() as i32,
+            flight_plan: details.and_then(|details| details.flight_plan).map(complete_aircraft),
+            logon_time: iso8601(logon_time),
+            last_updated: iso8601(now),
+        }
+    }
+}
+
+/// Fills in whichever of the three forms of the aircraft type the source left out.
+fn complete_aircraft(mut flight_plan: FlightPlan) -> FlightPlan {
+    let normalised = flight_plan.normalise();
+    if flight_plan.aircraft_short.is_empty() {
+        flight_plan.aircraft_short = normalised.aircraft_short;
+    }
+    if flight_plan.aircraft.is_empty() {
+        flight_plan.aircraft = flight_plan.aircraft_short.clone();
+    }
+    if flight_plan.aircraft_faa.is_empty() {
+        flight_plan.aircraft_faa = flight_plan.aircraft_short.clone();
+    }
+    flight_plan
+}
+
+#[derive(Serialize)]
+struct Controller<'a> {
+    cid: i32,
+    name: &'a str,
+    callsign: &'a str,
+    frequency: &'a str,
+    facility: u8,
+    rating: u8,
+    server: &'static str,
+    visual_range: u32,
+    text_atis: Option<Vec<String>>,
+    last_updated: String,
+    logon_time: String,
+}
+impl<'a> Controller<'a> {
+    fn new(controller: &'a LocalController, now: u64) -> Controller<'a> {
+        Controller {
+            cid: controller.cid.parse().unwrap_or_default(),
+            name: &controller.name,
+            callsign: &controller.callsign,
+            frequency: &controller.frequency,
+            facility: controller.facility,
+            rating: controller.rating,
+            server: SERVER_IDENT,
+            visual_range: controller.visual_range,
+            text_atis: None,
+            last_updated: iso8601(now),
+            logon_time: iso8601(controller.logon_time),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Server {
+    ident: &'static str,
+    hostname_or_ip: &'static str,
+    location: &'static str,
+    name: &'static str,
+    client_connections_allowed: bool,
+    is_sweatbox: bool,
+}
+
+const FACILITIES: [(u8, &str, &str); 7] = [
+    (0, "OBS", "Observer"),
+    (1, "FSS", "Flight Service Station"),
+    (2, "DEL", "Clearance Delivery"),
+    (3, "GND", "Ground"),
+    (4, "TWR", "Tower"),
+    (5, "APP", "Approach/Departure"),
+    (6, "CTR", "Enroute"),
+];
+
+
+/// Formats a Unix timestamp as e.g. `2024-01-31T18:05:09Z`.
+fn iso8601(timestamp: u64) -> String {
+    let (year, month, day, hours, minutes, seconds) = civil_time(timestamp);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hours, minutes, seconds)
+}
+
+/// Formats a Unix timestamp as e.g. `20240131180509`, as used by `general.update`.
+fn compact_timestamp(timestamp: u64) -> String {
+    let (year, month, day, hours, minutes, seconds) = civil_time(timestamp);
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}", year, month, day, hours, minutes, seconds)
+}
+
+/// Splits a Unix timestamp into its UTC date and time components.
+pub fn civil_time(timestamp: u64) -> (i64, u64, u64, u64, u64, u64) {
+    let days = (timestamp / 86400) as i64;
+    let seconds_of_day = timestamp % 86400;
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, seconds_of_day / 3600, (seconds_of_day % 3600) / 60, seconds_of_day % 60)
+}
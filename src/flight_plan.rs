@@ -0,0 +1,431 @@
+use serde::Serialize;
+
+use crate::vatsim::{FlightPlan, FlightRules};
+
+/// Rough true airspeed of Mach 1 at typical jet cruising levels, for turning a filed Mach number into knots
+const KNOTS_PER_MACH: f64 = 573.0;
+const KMH_TO_KNOTS: f64 = 0.539957;
+const METRES_TO_FEET: f64 = 3.28084;
+
+
+
+/// A flight plan with every field parsed out of the free-text forms pilots file, ready to be sent to
+/// an ATC client.
+///
+/// Fields which can't be parsed are left as `None`, and a description of the problem is added to
+/// [`NormalisedFlightPlan::warnings`].
+#[derive(Debug, Clone, Serialize)]
+pub struct NormalisedFlightPlan {
+    pub flight_rules: FlightRules,
+    /// Aircraft type and equipment in ICAO form, e.g. `B744/H-SDE3FGHIJ3J5M1RWXY/LB1D1`
+    pub aircraft_icao: String,
+    /// Aircraft type and equipment in FAA form, e.g. `H/B744/L`
+    pub aircraft_faa: String,
+    /// The ICAO type designator alone, e.g. `B744`
+    pub aircraft_short: String,
+    pub departure: String,
+    pub arrival: String,
+    pub alternate: String,
+    pub cruise_speed: Option<CruiseSpeed>,
+    pub cruise_level: Option<CruiseLevel>,
+    /// Estimated off-block time as (hours, minutes) UTC
+    pub departure_time: Option<(u8, u8)>,
+    pub enroute_time: Option<(u8, u8)>,
+    pub fuel_time: Option<(u8, u8)>,
+    pub route: String,
+    pub remarks: String,
+    pub revision_id: usize,
+    pub assigned_transponder: String,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "unit", content = "value", rename_all = "snake_case")]
+pub enum CruiseSpeed {
+    Knots(u32),
+    /// In hundredths, e.g. 79 for M0.79
+    Mach(u32),
+    KilometresPerHour(u32),
+}
+impl CruiseSpeed {
+    pub fn knots(&self) -> u32 {
+        match *self {
+            CruiseSpeed::Knots(knots) => knots,
+            CruiseSpeed::Mach(mach) => (mach as f64 / 100.0 * KNOTS_PER_MACH).round() as u32,
+            CruiseSpeed::KilometresPerHour(kmh) => (kmh as f64 * KMH_TO_KNOTS).round() as u32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "unit", content = "value", rename_all = "snake_case")]
+pub enum CruiseLevel {
+    /// In hundreds of feet, e.g. 350 for FL350
+    FlightLevel(u32),
+    Feet(u32),
+    Metres(u32),
+    Vfr,
+}
+impl CruiseLevel {
+    /// Returns the level in feet, or `None` for VFR or a level too high to express.
+    pub fn feet(&self) -> Option<u32> {
+        match *self {
+            CruiseLevel::FlightLevel(level) => level.checked_mul(100),
+            CruiseLevel::Feet(feet) => Some(feet),
+            CruiseLevel::Metres(metres) => Some((metres as f64 * METRES_TO_FEET).round() as u32),
+            CruiseLevel::Vfr => None,
+        }
+    }
+}
+
+impl From<&FlightPlan> for NormalisedFlightPlan {
+    fn from(value: &FlightPlan) -> Self {
+        let mut warnings = vec![];
+
+        let aircraft_short = match value.aircraft_short.is_empty() {
+            true => type_designator(&value.aircraft_faa, &value.aircraft),
+            false => value.aircraft_short.clone(),
+        };
+        if aircraft_short.is_empty() {
+            warnings.push(String::from("No aircraft type"));
+        }
+
+        let cruise_speed = parse_speed(&value.cruise_tas);
+        if cruise_speed.is_none() && !value.cruise_tas.is_empty() {
+            warnings.push(format!("Unrecognised cruise speed '{}'", value.cruise_tas));
+        }
+        let vfr = matches!(value.flight_rules, FlightRules::VFR | FlightRules::SVFR | FlightRules::DVFR);
+        let cruise_level = parse_level(&value.altitude, vfr);
+        if cruise_level.is_none() && !value.altitude.is_empty() {
+            warnings.push(format!("Unrecognised cruise level '{}'", value.altitude));
+        }
+        if cruise_level == Some(CruiseLevel::Vfr) && !vfr {
+            warnings.push(String::from("VFR cruise level on an IFR flight plan"));
+        }
+
+        let mut time = |name: &str, time: &str, clock: bool| {
+            let parsed = parse_time(time).filter(|(hours, _)| !clock || *hours < 24);
+            if parsed.is_none() && !time.is_empty() {
+                warnings.push(format!("Unrecognised {} '{}'", name, time));
+            }
+            parsed
+        };
+        let departure_time = time("departure time", &value.departure_time, true);
+        let enroute_time = time("enroute time", &value.enroute_time, false);
+        let fuel_time = time("fuel time", &value.fuel_time, false);
+        if let (Some(enroute), Some(fuel)) = (enroute_time, fuel_time) {
+            if fuel < enroute {
+                warnings.push(String::from("Fuel endurance is shorter than the enroute time"));
+            }
+        }
+
+        for (name, icao) in [("departure", &value.departure_icao), ("arrival", &value.arrival_icao)] {
+            if icao.is_empty() {
+                warnings.push(format!("No {} aerodrome", name));
+            } else if icao.len() != 4 || !icao.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                warnings.push(format!("Invalid {} aerodrome '{}'", name, icao));
+            }
+        }
+
+        NormalisedFlightPlan {
+            flight_rules: value.flight_rules.clone(),
+            aircraft_icao: value.aircraft.clone(),
+            aircraft_faa: value.aircraft_faa.clone(),
+            aircraft_short,
+            departure: value.departure_icao.trim().to_uppercase(),
+            arrival: value.arrival_icao.trim().to_uppercase(),
+            alternate: value.alternate_icao.trim().to_uppercase(),
+            cruise_speed,
+            cruise_level,
+            departure_time,
+            enroute_time,
+            fuel_time,
+            route: value.route.clone(),
+            remarks: value.remarks.clone(),
+            revision_id: value.revision_id,
+            assigned_transponder: value.assigned_transponder.clone(),
+            warnings,
+        }
+    }
+}
+
+impl From<NormalisedFlightPlan> for fsd_interface::FlightPlan {
+    fn from(value: NormalisedFlightPlan) -> Self {
+        let hhmm = |time: Option<(u8, u8)>| time.map(|(hours, mins)| hours as u16 * 100 + mins as u16).unwrap_or_default();
+        let (hours_enroute, mins_enroute) = value.enroute_time.unwrap_or_default();
+        let (hours_fuel, mins_fuel) = value.fuel_time.unwrap_or_default();
+        Self {
+            flight_rules: match value.flight_rules {
+                FlightRules::DVFR => fsd_interface::FlightRules::DVFR,
+                FlightRules::VFR => fsd_interface::FlightRules::VFR,
+                FlightRules::SVFR => fsd_interface::FlightRules::SVFR,
+                FlightRules::IFR => fsd_interface::FlightRules::IFR,
+            },
+            // Classic clients expect the FAA form, but not every plan has one
+            ac_type: match value.aircraft_faa.is_empty() {
+                true => value.aircraft_icao,
+                false => value.aircraft_faa,
+            },
+            filed_tas: value.cruise_speed.map(|speed| speed.knots()).unwrap_or_default().min(u16::MAX as u32) as u16,
+            origin: value.departure,
+            etd: hhmm(value.departure_time),
+            // The feeds only carry the planned departure time
+            atd: 0,
+            cruise_level: value.cruise_level.and_then(|level| level.feet()).unwrap_or_default(),
+            destination: value.arrival,
+            hours_enroute,
+            mins_enroute,
+            hours_fuel,
+            mins_fuel,
+            alternate: value.alternate,
+            remarks: value.remarks,
+            route: value.route,
+        }
+    }
+}
+
+
+/// Picks the type designator out of a full aircraft string. In FAA form (`H/B744/L`) it's the longest
+/// part; in ICAO form (`B744/H-SDE3FGHIJ3J5M1RWXY/LB1D1`) it's the first.
+fn type_designator(faa: &str, icao: &str) -> String {
+    match icao.split('/').next().filter(|designator| !designator.is_empty()) {
+        Some(designator) => designator.to_owned(),
+        None => faa.split('/').max_by_key(|part| part.len()).unwrap_or_default().to_owned(),
+    }
+}
+
+/// Parses a cruising speed, either as a bare number of knots (`450`) or Mach number (`0.79`), or in ICAO form
+/// (`N0450`, `M079`, `K0830`). Mach numbers may also be written with a decimal point, e.g. `M0.79` or `M.79`.
+fn parse_speed(speed: &str) -> Option<CruiseSpeed> {
+    let speed = speed.trim().to_uppercase();
+    if let Ok(knots) = speed.parse::<u32>() {
+        return Some(CruiseSpeed::Knots(knots));
+    }
+    if speed.contains('.') && !speed.starts_with('M') {
+        return parse_mach(&speed);
+    }
+    let (unit, value) = speed.split_at_checked(1)?;
+    match unit {
+        "N" => value.parse().ok().map(CruiseSpeed::Knots),
+        "M" => parse_mach(value),
+        "K" => value.parse().ok().map(CruiseSpeed::KilometresPerHour),
+        _ => None,
+    }
+}
+
+/// Parses a Mach number, either in hundredths (`079`) or with a decimal point (`0.79`, `.79`).
+fn parse_mach(mach: &str) -> Option<CruiseSpeed> {
+    if !mach.contains('.') {
+        return mach.parse().ok().map(CruiseSpeed::Mach);
+    }
+    let mach = mach.parse::<f64>().ok().filter(|mach| *mach > 0.0 && *mach < 5.0)?;
+    Some(CruiseSpeed::Mach((mach * 100.0).round() as u32))
+}
+
+/// Parses a cruising level, either in feet (`35000`), as a flight level (`FL350`), or in ICAO form
+/// (`F350`, `A045`, `S1130`, `M0610`, `VFR`).
+///
+/// A bare number below 1000 is taken to be a flight level on IFR plans, e.g. `350`, but an altitude in feet
+/// on VFR plans, which are often filed at e.g. `500`. Written with a leading zero, e.g. `085`, it's always a
+/// flight level. Levels too high to express in feet or metres give `None`.
+pub fn parse_level(level: &str, vfr: bool) -> Option<CruiseLevel> {
+    let level = level.trim().to_uppercase();
+    if level == "VFR" {
+        return Some(CruiseLevel::Vfr);
+    }
+    if let Ok(value) = level.parse::<u32>() {
+        return Some(match value < 1000 && (!vfr || level.starts_with('0')) {
+            true => CruiseLevel::FlightLevel(value),
+            false => CruiseLevel::Feet(value),
+        });
+    }
+    if let Some(value) = level.strip_prefix("FL") {
+        return value.parse().ok().map(CruiseLevel::FlightLevel);
+    }
+    let (unit, value) = level.split_at_checked(1)?;
+    let value = value.parse::<u32>().ok()?;
+    match unit {
+        "F" => Some(CruiseLevel::FlightLevel(value)),
+        "A" => value.checked_mul(100).map(CruiseLevel::Feet),
+        "S" | "M" => value.checked_mul(10).map(CruiseLevel::Metres),
+        _ => None,
+    }
+}
+
+/// Parses a time as `HHMM`, `HMM` or `HH:MM`.
+fn parse_time(time: &str) -> Option<(u8, u8)> {
+    let time = time.trim().replace(':', "");
+    if time.is_empty() || time.len() > 4 || !time.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value = time.parse::<u16>().ok()?;
+    let (hours, mins) = (value / 100, value % 100);
+    match mins < 60 {
+        true => Some((hours as u8, mins as u8)),
+        false => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ivao::IvaoProvider, network::NetworkProvider, vatsim::VatsimProvider};
+
+    const VATSIM_FIXTURE: &str = include_str!("../tests/fixtures/vatsim-data.json");
+    const IVAO_FIXTURE: &str = include_str!("../tests/fixtures/whazzup.json");
+
+    /// The flight plans from a feed fixture, keyed by callsign.
+    fn flight_plans(mut provider: impl NetworkProvider, fixture: &str) -> Vec<(String, FlightPlan)> {
+        let data = provider.parse(fixture).unwrap().unwrap();
+        data.pilots.into_iter().chain(data.prefiles)
+            .filter_map(|details| Some((details.callsign, details.flight_plan?)))
+            .collect()
+    }
+
+    fn normalised(provider: impl NetworkProvider, fixture: &str, callsign: &str) -> NormalisedFlightPlan {
+        let (_, flight_plan) = flight_plans(provider, fixture).into_iter().find(|(cs, _)| cs == callsign).unwrap();
+        flight_plan.normalise()
+    }
+
+    #[test]
+    fn normalises_a_vatsim_ifr_plan() {
+        let flight_plan = normalised(VatsimProvider::default(), VATSIM_FIXTURE, "BAW256");
+        assert_eq!(flight_plan.aircraft_icao, "B772/H-SDE2E3FGHIJ2J3J4J5M1RWXY/LB1D1");
+        assert_eq!(flight_plan.aircraft_faa, "H/B772/L");
+        assert_eq!(flight_plan.aircraft_short, "B772");
+        assert_eq!(flight_plan.cruise_speed, Some(CruiseSpeed::Knots(488)));
+        assert_eq!(flight_plan.cruise_level, Some(CruiseLevel::Feet(35000)));
+        assert_eq!(flight_plan.departure_time, Some((18, 45)));
+        assert_eq!(flight_plan.enroute_time, Some((7, 45)));
+        assert_eq!(flight_plan.fuel_time, Some((9, 30)));
+        assert_eq!(flight_plan.assigned_transponder, "2201");
+        assert!(flight_plan.warnings.is_empty(), "{:?}", flight_plan.warnings);
+
+        let fsd = fsd_interface::FlightPlan::from(flight_plan);
+        assert_eq!(fsd.ac_type, "H/B772/L");
+        assert_eq!((fsd.filed_tas, fsd.cruise_level, fsd.etd, fsd.atd), (488, 35000, 1845, 0));
+        assert_eq!((fsd.hours_enroute, fsd.mins_enroute, fsd.hours_fuel, fsd.mins_fuel), (7, 45, 9, 30));
+    }
+
+    #[test]
+    fn normalises_vatsim_vfr_plans_and_prefiles() {
+        let flight_plan = normalised(VatsimProvider::default(), VATSIM_FIXTURE, "N172SP");
+        assert_eq!(flight_plan.flight_rules, FlightRules::VFR);
+        assert_eq!(flight_plan.cruise_level, Some(CruiseLevel::Feet(4500)));
+        assert_eq!(flight_plan.alternate, "");
+        assert!(flight_plan.warnings.is_empty(), "{:?}", flight_plan.warnings);
+
+        let flight_plan = normalised(VatsimProvider::default(), VATSIM_FIXTURE, "EZY45KP");
+        assert_eq!(flight_plan.cruise_level, Some(CruiseLevel::FlightLevel(370)));
+        assert_eq!(flight_plan.cruise_level.unwrap().feet(), Some(37000));
+        assert_eq!(flight_plan.departure_time, Some((19, 30)));
+    }
+
+    #[test]
+    fn normalises_ivao_plans() {
+        let flight_plan = normalised(IvaoProvider::default(), IVAO_FIXTURE, "AFR1234");
+        assert_eq!(flight_plan.aircraft_short, "A320");
+        assert_eq!(flight_plan.cruise_speed, Some(CruiseSpeed::Knots(450)));
+        assert_eq!(flight_plan.cruise_level, Some(CruiseLevel::FlightLevel(350)));
+        assert_eq!(flight_plan.departure_time, Some((17, 0)));
+        assert_eq!(flight_plan.enroute_time, Some((1, 45)));
+        assert!(flight_plan.warnings.is_empty(), "{:?}", flight_plan.warnings);
+
+        let fsd = fsd_interface::FlightPlan::from(flight_plan);
+        // Without an FAA form, the ICAO one is sent
+        assert_eq!(fsd.ac_type, "A320/M-SDE2E3FGIJ1RWY/LB1");
+
+        let flight_plan = normalised(IvaoProvider::default(), IVAO_FIXTURE, "DEABC");
+        assert_eq!(flight_plan.cruise_level, Some(CruiseLevel::Vfr));
+        assert_eq!(flight_plan.cruise_speed, Some(CruiseSpeed::Knots(108)));
+    }
+
+    #[test]
+    fn every_fixture_plan_normalises_without_warnings() {
+        let plans = flight_plans(VatsimProvider::default(), VATSIM_FIXTURE).into_iter().chain(flight_plans(IvaoProvider::default(), IVAO_FIXTURE));
+        for (callsign, flight_plan) in plans {
+            let normalised = flight_plan.normalise();
+            assert!(normalised.warnings.is_empty(), "{}: {:?}", callsign, normalised.warnings);
+            assert!(normalised.cruise_level.is_some() && normalised.cruise_speed.is_some(), "{}", callsign);
+        }
+    }
+
+    #[test]
+    fn warns_about_unusable_fields() {
+        let (_, mut flight_plan) = flight_plans(VatsimProvider::default(), VATSIM_FIXTURE).into_iter().next().unwrap();
+        flight_plan.altitude = String::from("VFR");
+        flight_plan.cruise_tas = String::from("fast");
+        flight_plan.departure_time = String::from("2575");
+        flight_plan.fuel_time = String::from("0100");
+        flight_plan.arrival_icao = String::new();
+        flight_plan.aircraft_short = String::new();
+        let normalised = flight_plan.normalise();
+        assert_eq!(normalised.aircraft_short, "B772");
+        assert_eq!(normalised.cruise_speed, None);
+        assert_eq!(normalised.departure_time, None);
+        assert_eq!(normalised.warnings, [
+            "Unrecognised cruise speed 'fast'",
+            "VFR cruise level on an IFR flight plan",
+            "Unrecognised departure time '2575'",
+            "Fuel endurance is shorter than the enroute time",
+            "No arrival aerodrome",
+        ]);
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("1845"), Some((18, 45)));
+        assert_eq!(parse_time("945"), Some((9, 45)));
+        assert_eq!(parse_time("0"), Some((0, 0)));
+        assert_eq!(parse_time("07:30"), Some((7, 30)));
+        assert_eq!(parse_time(" 0100 "), Some((1, 0)));
+        // Enroute and fuel times may exceed a day
+        assert_eq!(parse_time("2600"), Some((26, 0)));
+        assert_eq!(parse_time("1275"), None);
+        assert_eq!(parse_time("12345"), None);
+        assert_eq!(parse_time("12h30"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_level("35000", false), Some(CruiseLevel::Feet(35000)));
+        assert_eq!(parse_level("FL350", false), Some(CruiseLevel::FlightLevel(350)));
+        assert_eq!(parse_level("fl085", true), Some(CruiseLevel::FlightLevel(85)));
+        assert_eq!(parse_level("F350", false), Some(CruiseLevel::FlightLevel(350)));
+        assert_eq!(parse_level("A045", true), Some(CruiseLevel::Feet(4500)));
+        assert_eq!(parse_level("S1130", false), Some(CruiseLevel::Metres(11300)));
+        assert_eq!(parse_level("M0610", false), Some(CruiseLevel::Metres(6100)));
+        assert_eq!(parse_level("VFR", true), Some(CruiseLevel::Vfr));
+        assert_eq!(parse_level("350", false), Some(CruiseLevel::FlightLevel(350)));
+        assert_eq!(parse_level("500", true), Some(CruiseLevel::Feet(500)));
+        assert_eq!(parse_level("085", true), Some(CruiseLevel::FlightLevel(85)));
+        assert_eq!(parse_level("4500", true), Some(CruiseLevel::Feet(4500)));
+        assert_eq!(parse_level("high", false), None);
+        assert_eq!(parse_level("", false), None);
+        assert_eq!(parse_level("A99999999", false), None);
+        assert_eq!(parse_level("S999999999", false), None);
+        assert_eq!(parse_level("FL99999999999", false), None);
+        assert_eq!(parse_level("F99999999", false).and_then(|level| level.feet()), None);
+        assert_eq!(CruiseLevel::Metres(11300).feet(), Some(37073));
+    }
+
+    #[test]
+    fn parses_speeds() {
+        assert_eq!(parse_speed("450"), Some(CruiseSpeed::Knots(450)));
+        assert_eq!(parse_speed("N0450"), Some(CruiseSpeed::Knots(450)));
+        assert_eq!(parse_speed("K0830"), Some(CruiseSpeed::KilometresPerHour(830)));
+        assert_eq!(parse_speed("M079"), Some(CruiseSpeed::Mach(79)));
+        assert_eq!(parse_speed("m082"), Some(CruiseSpeed::Mach(82)));
+        assert_eq!(parse_speed("M0.79"), Some(CruiseSpeed::Mach(79)));
+        assert_eq!(parse_speed("M.85"), Some(CruiseSpeed::Mach(85)));
+        assert_eq!(parse_speed("0.78"), Some(CruiseSpeed::Mach(78)));
+        assert_eq!(parse_speed("M1.6"), Some(CruiseSpeed::Mach(160)));
+        assert_eq!(parse_speed("fast"), None);
+        assert_eq!(parse_speed("M"), None);
+        assert_eq!(parse_speed(""), None);
+        assert_eq!(CruiseSpeed::Mach(79).knots(), 453);
+        assert_eq!(CruiseSpeed::KilometresPerHour(830).knots(), 448);
+    }
+}
@@ -493,4 +493,142 @@ impl StringData {
             )
         }
     }
-}
\ No newline at end of file
+}
+
+/// Weather as reported by the FSUIPC New Weather Interface. The layout follows `NewWeather.h` in the FSUIPC SDK.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct NewWeather {
+    pub command: u16,
+    pub flags: u16,
+    pub signature: u32,
+    pub icao: [u8; 4],
+    pub dynamics: u16,
+    pub seconds: u16,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Metres AMSL
+    pub elevation: i16,
+    pub timestamp: u32,
+    pub pressure: NewPress,
+    pub vis: NewVis,
+    pub temp_count: i16,
+    pub temp: [NewTemp; 24],
+    pub winds_count: i16,
+    pub wind: [NewWind; 24],
+    pub clouds_count: i16,
+    pub cloud: [NewCloud; 16],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct NewPress {
+    /// Millibars * 16
+    pub pressure: u16,
+    pub drift: i16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct NewVis {
+    /// Metres AMSL
+    pub upper_alt: u16,
+    /// Metres AMSL
+    pub lower_alt: u16,
+    /// Statute miles * 100
+    pub range: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct NewTemp {
+    /// Metres AMSL
+    pub alt: u16,
+    /// Degrees C
+    pub day: i16,
+    pub day_night_var: i16,
+    /// Degrees C
+    pub dew_point: i16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct NewWind {
+    /// Metres AMSL
+    pub upper_alt: u16,
+    /// Knots
+    pub speed: u16,
+    /// Knots
+    pub gust: u16,
+    /// Degrees true * 65536 / 360
+    pub direction: u16,
+    pub turbulence: u8,
+    pub shear: u8,
+    pub variance: u16,
+    pub speed_fract: u16,
+    pub gap_above: u8,
+    pub spare: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct NewCloud {
+    /// Metres AMSL
+    pub upper_alt: u16,
+    /// Metres AMSL
+    pub lower_alt: u16,
+    pub deviation: u16,
+    /// Octas
+    pub coverage: u8,
+    /// 8 = stratus, 9 = cumulus, 10 = cumulonimbus
+    pub cloud_type: u8,
+    pub turbulence: u8,
+    pub icing: u8,
+    pub precip_base: u8,
+    /// 0 = none, 1 = rain, 2 = snow
+    pub precip_type: u8,
+    /// 0 - 5
+    pub precip_rate: u8,
+    pub top_shape: u8,
+    pub spare: [u8; 2],
+}
+
+/// Returns the weather at the user's aircraft.
+pub fn get_weather_at_aircraft() -> Result<NewWeather, Error> {
+    read(0xCC00)
+}
+
+/// An outstanding request for the weather at a station, made with [`request_weather_at_station`].
+#[derive(Debug, Clone, Copy)]
+pub struct StationWeatherRequest {
+    icao: [u8; 4],
+    previous: NewWeather,
+}
+
+/// Asks the simulator for the weather at a particular weather station. It arrives asynchronously once the
+/// simulator has looked the station up, and can be collected with [`read_requested_weather`].
+pub fn request_weather_at_station(icao: &str) -> Result<StationWeatherRequest, Error> {
+    let mut requested = [b' '; 4];
+    for (dest, src) in requested.iter_mut().zip(icao.to_uppercase().bytes()) {
+        *dest = src;
+    }
+    let previous: NewWeather = read(0xC400)?;
+    unsafe {
+        let mut result = 0;
+        if FSUIPC_Write(0xD800, requested.len() as u32, requested.as_ptr() as *const _, &mut result) != 1 || FSUIPC_Process(&mut result) != 1 {
+            let result: Error = mem::transmute(result);
+            return Err(result);
+        }
+    }
+    Ok(StationWeatherRequest { icao: requested, previous })
+}
+
+/// Returns the weather for a request made with [`request_weather_at_station`], or `None` if it hasn't arrived yet.
+pub fn read_requested_weather(request: &StationWeatherRequest) -> Result<Option<NewWeather>, Error> {
+    let weather: NewWeather = read(0xC400)?;
+    let (icao, timestamp, previous_icao, previous_timestamp) = (weather.icao, weather.timestamp, request.previous.icao, request.previous.timestamp);
+    match icao == request.icao && (timestamp != previous_timestamp || previous_icao == request.icao) {
+        true => Ok(Some(weather)),
+        false => Ok(None),
+    }
+}
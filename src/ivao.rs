@@ -0,0 +1,349 @@
+use serde::Deserialize;
+
+use crate::{flight_plan::{self, CruiseLevel}, network::{NetworkData, NetworkProvider}, vatsim::{Atis, Controller, Details, FlightPlan, FlightRules}};
+
+const IVAO_WHAZZUP_URL: &str = "https://api.ivao.aero/v2/tracker/whazzup";
+const KMH_TO_KNOTS: f64 = 0.539957;
+
+
+
+/// Reads IVAO's Whazzup v2 JSON feed, mapping it onto the VATSIM data model.
+///
+/// Whazzup doesn't publish members' names, prefiled flight plans or altimeter settings, so those are
+/// left empty or at their standard values.
+#[derive(Default)]
+pub struct IvaoProvider {
+    last_update: Option<String>,
+}
+
+impl NetworkProvider for IvaoProvider {
+    fn name(&self) -> &'static str {
+        "IVAO"
+    }
+
+    fn data_url(&self) -> &'static str {
+        IVAO_WHAZZUP_URL
+    }
+
+    fn parse(&mut self, body: &str) -> Result<Option<NetworkData>, String> {
+        let whazzup = serde_json::from_str::<Whazzup>(body).map_err(|e| e.to_string())?;
+        if whazzup.updated_at.is_some() && self.last_update == whazzup.updated_at {
+            return Ok(None);
+        }
+        self.last_update = whazzup.updated_at;
+        let mut data = NetworkData {
+            pilots: whazzup.clients.pilots.into_iter().filter_map(Pilot::into_details).collect(),
+            ..NetworkData::default()
+        };
+        for controller in whazzup.clients.atcs.into_iter().map(Atc::into_controller) {
+            if controller.callsign.ends_with("_ATIS") {
+                data.atis.push(Atis { callsign: controller.callsign.clone(), text_atis: controller.text_atis.clone() });
+            }
+            data.controllers.push(controller);
+        }
+        Ok(Some(data))
+    }
+}
+
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Whazzup {
+    updated_at: Option<String>,
+    clients: Clients,
+}
+
+#[derive(Deserialize)]
+struct Clients {
+    #[serde(default)]
+    pilots: Vec<Pilot>,
+    #[serde(default)]
+    atcs: Vec<Atc>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Pilot {
+    user_id: i32,
+    callsign: String,
+    last_track: Option<Track>,
+    flight_plan: Option<IvaoFlightPlan>,
+}
+impl Pilot {
+    /// Pilots who haven't sent a position yet have no track, and are skipped.
+    fn into_details(self) -> Option<Details> {
+        let track = self.last_track?;
+        Some(Details {
+            cid: self.user_id,
+            name: String::new(),
+            callsign: self.callsign,
+            transponder: format!("{:04}", track.transponder),
+            latitude: track.latitude,
+            longitude: track.longitude,
+            altitude: track.altitude,
+            groundspeed: track.ground_speed,
+            heading: track.heading,
+            qnh_i_hg: 29.92,
+            flight_plan: self.flight_plan.map(FlightPlan::from),
+            prefiled: false,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Track {
+    latitude: f64,
+    longitude: f64,
+    altitude: i32,
+    ground_speed: u32,
+    heading: u32,
+    transponder: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IvaoFlightPlan {
+    revision: usize,
+    aircraft_id: Option<String>,
+    aircraft: Option<IvaoAircraft>,
+    aircraft_equipments: Option<String>,
+    aircraft_transponder_types: Option<String>,
+    departure_id: Option<String>,
+    arrival_id: Option<String>,
+    alternative_id: Option<String>,
+    route: Option<String>,
+    remarks: Option<String>,
+    speed: Option<String>,
+    level: Option<String>,
+    flight_rules: Option<String>,
+    /// Seconds after midnight UTC
+    departure_time: Option<u32>,
+    /// Seconds
+    eet: Option<u32>,
+    /// Seconds
+    endurance: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IvaoAircraft {
+    icao_code: String,
+    wake_turbulence: Option<String>,
+}
+
+impl From<IvaoFlightPlan> for FlightPlan {
+    fn from(value: IvaoFlightPlan) -> Self {
+        // Written out in ICAO form, e.g. "A320/M-SDFGIRWY/LB1", as VATSIM does for ICAO flight plans
+        let icao_code = value.aircraft.as_ref().map(|aircraft| aircraft.icao_code.clone()).or(value.aircraft_id).unwrap_or_default();
+        let wake_turbulence = value.aircraft.and_then(|aircraft| aircraft.wake_turbulence).unwrap_or_default();
+        let aircraft = match (value.aircraft_equipments, value.aircraft_transponder_types) {
+            (Some(equipment), Some(transponder)) if !wake_turbulence.is_empty() => format!("{}/{}-{}/{}", icao_code, wake_turbulence, equipment, transponder),
+            _ => icao_code.clone(),
+        };
+
+        let flight_rules = match value.flight_rules.as_deref() {
+            Some("V") => FlightRules::VFR,
+            _ => FlightRules::IFR,
+        };
+        FlightPlan {
+            altitude: value.level.as_deref().map(|level| altitude(level, flight_rules == FlightRules::VFR)).unwrap_or_default(),
+            flight_rules,
+            aircraft,
+            aircraft_faa: String::new(),
+            aircraft_short: icao_code,
+            departure_icao: value.departure_id.unwrap_or_default(),
+            arrival_icao: value.arrival_id.unwrap_or_default(),
+            alternate_icao: value.alternative_id.unwrap_or_default(),
+            cruise_tas: value.speed.as_deref().map(cruise_tas).unwrap_or_default(),
+            departure_time: value.departure_time.map(hhmm).unwrap_or_default(),
+            enroute_time: value.eet.map(hhmm).unwrap_or_default(),
+            fuel_time: value.endurance.map(hhmm).unwrap_or_default(),
+            remarks: value.remarks.unwrap_or_default(),
+            route: value.route.unwrap_or_default(),
+            revision_id: value.revision,
+            assigned_transponder: String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Atc {
+    user_id: i32,
+    callsign: String,
+    rating: u8,
+    atc_session: AtcSession,
+    atis: Option<IvaoAtis>,
+}
+impl Atc {
+    fn into_controller(self) -> Controller {
+        let position = self.atc_session.position.unwrap_or_else(|| self.callsign.rsplit('_').next().unwrap_or_default().to_owned());
+        let (facility, visual_range) = match position.as_str() {
+            "FSS" => (1, 1500),
+            "DEL" => (2, 20),
+            "GND" => (3, 20),
+            "TWR" | "ATIS" => (4, 50),
+            "APP" | "DEP" => (5, 150),
+            "CTR" => (6, 600),
+            _ => (0, 0),
+        };
+        Controller {
+            cid: self.user_id,
+            name: String::new(),
+            callsign: self.callsign,
+            frequency: format!("{:.3}", self.atc_session.frequency),
+            facility,
+            rating: atc_rating(self.rating),
+            visual_range,
+            text_atis: self.atis.map(|atis| atis.lines).filter(|lines| !lines.is_empty()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AtcSession {
+    frequency: f64,
+    position: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IvaoAtis {
+    #[serde(default)]
+    lines: Vec<String>,
+}
+
+
+/// Maps an IVAO ATC rating onto the nearest VATSIM one.
+fn atc_rating(rating: u8) -> i8 {
+    match rating {
+        2..=4 => 2, // AS1-AS3 -> S1
+        5 => 3,     // ADC -> S2
+        6 => 4,     // APC -> S3
+        7 => 5,     // ACC -> C1
+        8 => 7,     // SEC -> C3
+        9 => 8,     // SAI -> I1
+        10 => 10,   // CAI -> I3
+        _ => 1,
+    }
+}
+
+/// Converts an ICAO cruising level, e.g. `F350`, `A045`, `S1130` or `VFR`, to the form used by VATSIM.
+/// A level which can't be understood is left as it is.
+fn altitude(level: &str, vfr: bool) -> String {
+    match flight_plan::parse_level(level, vfr) {
+        Some(CruiseLevel::FlightLevel(flight_level)) => format!("FL{:03}", flight_level),
+        Some(CruiseLevel::Vfr) => String::from("VFR"),
+        Some(cruise_level) => cruise_level.feet().map(|feet| feet.to_string()).unwrap_or_else(|| level.to_owned()),
+        None => level.to_owned(),
+    }
+}
+
+/// Converts an ICAO cruising speed, e.g. `N0450` or `K0830`, to a true airspeed in knots.
+/// Mach numbers are left as they are.
+fn cruise_tas(speed: &str) -> String {
+    let value = speed.get(1..).and_then(|value| value.parse::<u32>().ok()).unwrap_or_default();
+    match speed.chars().next() {
+        Some('N') => value.to_string(),
+        Some('K') => ((value as f64 * KMH_TO_KNOTS).round() as u32).to_string(),
+        _ => speed.to_owned(),
+    }
+}
+
+/// Formats a number of seconds as `HHMM`.
+fn hhmm(seconds: u32) -> String {
+    format!("{:02}{:02}", seconds / 3600 % 100, seconds % 3600 / 60)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/whazzup.json");
+
+    fn parse_fixture() -> NetworkData {
+        IvaoProvider::default().parse(FIXTURE).unwrap().unwrap()
+    }
+
+    #[test]
+    fn maps_pilots_with_a_track() {
+        let data = parse_fixture();
+        let callsigns: Vec<&str> = data.pilots.iter().map(|pilot| pilot.callsign.as_str()).collect();
+        assert_eq!(callsigns, ["AFR1234", "DEABC"]);
+
+        let pilot = &data.pilots[0];
+        assert_eq!(pilot.cid, 512345);
+        assert_eq!(pilot.transponder, "2314");
+        assert_eq!((pilot.latitude, pilot.longitude), (45.20519, 2.87401));
+        assert_eq!(pilot.altitude, 35012);
+        assert_eq!(pilot.groundspeed, 462);
+        assert_eq!(pilot.heading, 205);
+        assert_eq!(pilot.qnh_i_hg, 29.92);
+        assert!(data.prefiles.is_empty());
+    }
+
+    #[test]
+    fn maps_flight_plans_to_the_vatsim_model() {
+        let data = parse_fixture();
+        let flight_plan = data.pilots[0].flight_plan.as_ref().unwrap();
+        assert_eq!(flight_plan.flight_rules, FlightRules::IFR);
+        assert_eq!(flight_plan.aircraft, "A320/M-SDE2E3FGIJ1RWY/LB1");
+        assert_eq!(flight_plan.aircraft_short, "A320");
+        assert_eq!((flight_plan.departure_icao.as_str(), flight_plan.arrival_icao.as_str(), flight_plan.alternate_icao.as_str()), ("LFPG", "LEMD", "LEBL"));
+        assert_eq!(flight_plan.cruise_tas, "450");
+        assert_eq!(flight_plan.altitude, "FL350");
+        assert_eq!(flight_plan.departure_time, "1700");
+        assert_eq!(flight_plan.enroute_time, "0145");
+        assert_eq!(flight_plan.fuel_time, "0330");
+        assert_eq!(flight_plan.revision_id, 3);
+
+        let flight_plan = data.pilots[1].flight_plan.as_ref().unwrap();
+        assert_eq!(flight_plan.flight_rules, FlightRules::VFR);
+        assert_eq!(flight_plan.cruise_tas, "108");
+        assert_eq!(flight_plan.altitude, "VFR");
+        assert_eq!(flight_plan.alternate_icao, "");
+        assert_eq!(flight_plan.remarks, "");
+    }
+
+    #[test]
+    fn maps_controllers_and_atis() {
+        let data = parse_fixture();
+        let tower = data.controllers.iter().find(|controller| controller.callsign == "LFPG_TWR").unwrap();
+        assert_eq!((tower.facility, tower.rating, tower.visual_range), (4, 3, 50));
+        assert_eq!(tower.frequency, "118.650");
+        assert_eq!(tower.text_atis.as_ref().unwrap().len(), 3);
+
+        // Without a position, the facility comes from the callsign
+        let centre = data.controllers.iter().find(|controller| controller.callsign == "EDGG_CTR").unwrap();
+        assert_eq!((centre.facility, centre.rating, centre.visual_range), (6, 5, 600));
+        assert_eq!(centre.text_atis, None);
+
+        assert_eq!(data.atis.len(), 1);
+        assert_eq!(data.atis[0].callsign, "LFPG_ATIS");
+        assert_eq!(data.atis[0].text_atis.as_ref().unwrap()[0], "CHARLES DE GAULLE INFORMATION C");
+    }
+
+    #[test]
+    fn skips_unchanged_documents() {
+        let mut provider = IvaoProvider::default();
+        assert!(provider.parse(FIXTURE).unwrap().is_some());
+        assert!(provider.parse(FIXTURE).unwrap().is_none());
+    }
+
+    #[test]
+    fn converts_levels_and_speeds() {
+        assert_eq!(altitude("F080", false), "FL080");
+        assert_eq!(altitude("A045", true), "4500");
+        assert_eq!(altitude("S1130", false), "37073");
+        assert_eq!(altitude("VFR", true), "VFR");
+        assert_eq!(altitude("A99999999", false), "A99999999");
+        assert_eq!(altitude("S999999999", false), "S999999999");
+        assert_eq!(altitude("ABC", false), "ABC");
+        assert_eq!(cruise_tas("N0450"), "450");
+        assert_eq!(cruise_tas("K0830"), "448");
+        assert_eq!(cruise_tas("M079"), "M079");
+        assert_eq!(hhmm(0), "0000");
+        assert_eq!(hhmm(86399), "2359");
+    }
+}
@@ -1,4 +1,4 @@
-use std::ptr;
+use std::{path::Path, ptr};
 use windows_sys::{w, Win32::{Foundation::TRUE, System::Threading::CreateMutexW, UI::WindowsAndMessaging::{MessageBoxW, MB_ICONERROR}}};
 
 mod app;
@@ -7,8 +7,43 @@ mod vatsim;
 mod aircraft;
 mod worker;
 mod server;
+mod config;
+mod api;
+mod feed;
+mod weather;
+mod atis;
+mod controllers;
+mod network;
+mod ivao;
+mod flight_plan;
+mod matcher;
+mod auth;
+mod atc_state;
+mod amendments;
+mod encoding;
+mod capture;
+mod replay;
+mod commands;
 
 fn main() {
+    // Replay a protocol capture against the server, e.g. `--replay captures/euroscope.log tests/euroscope.golden.log`,
+    // adding `--update-golden` to write the golden transcript from this run instead of comparing with it
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--replay") {
+        let (Some(capture), Some(golden)) = (args.get(index + 1), args.get(index + 2)) else {
+            println!("Usage: --replay <capture file> <golden transcript> [--update-golden]");
+            std::process::exit(2);
+        };
+        match replay::run(Path::new(capture), Path::new(golden), args.iter().any(|arg| arg == "--update-golden")) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(error) => {
+                println!("Replay failed: {}", error);
+                std::process::exit(2);
+            },
+        }
+    }
+
     // Ensure only one instance is running
     if !check_unique_instance() {
         unsafe { MessageBoxW(0, w!("Traffic Viewer is already running!"), w!("Traffic Viewer"), MB_ICONERROR) };
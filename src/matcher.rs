@@ -0,0 +1,226 @@
+use std::{collections::HashMap, fs, time::SystemTime};
+
+use serde::Serialize;
+
+use crate::config::MatcherConfig;
+
+/// IATA to ICAO designators for airlines commonly flown in AI traffic packages
+const AIRLINE_DESIGNATORS: [(&str, &str); 48] = [
+    ("AA", "AAL"), ("AC", "ACA"), ("AF", "AFR"), ("AI", "AIC"), ("AY", "FIN"), ("AZ", "ITY"),
+    ("BA", "BAW"), ("BR", "EVA"), ("CA", "CCA"), ("CX", "CPA"), ("DL", "DAL"), ("DY", "NOZ"),
+    ("EI", "EIN"), ("EK", "UAE"), ("ET", "ETH"), ("EW", "EWG"), ("EY", "ETD"), ("FR", "RYR"),
+    ("IB", "IBE"), ("JL", "JAL"), ("KE", "KAL"), ("KL", "KLM"), ("LH", "DLH"), ("LO", "LOT"),
+    ("LX", "SWR"), ("MS", "MSR"), ("NH", "ANA"), ("NZ", "ANZ"), ("OS", "AUA"), ("OZ", "AAR"),
+    ("QF", "QFA"), ("QR", "QTR"), ("SA", "SAA"), ("SK", "SAS"), ("SN", "BEL"), ("SQ", "SIA"),
+    ("SU", "AFL"), ("TK", "THY"), ("TP", "TAP"), ("U2", "EZY"), ("UA", "UAL"), ("UX", "AEA"),
+    ("VS", "VIR"), ("VY", "VLG"), ("W6", "WZZ"), ("WN", "SWA"), ("WS", "WJA"), ("AS", "ASA"),
+];
+
+
+
+/// Works out which network callsign a simulator AI aircraft corresponds to.
+///
+/// The rules are tried in order, and the first callsign the network knows about wins:
+/// 1. the manual overrides, made with the `.match` command or in the override file
+/// 2. the alias table in the config, where `BAW*` style keys replace a prefix and the longest prefix wins
+/// 3. the callsign exactly as the simulator gives it
+/// 4. the callsign upper-cased with spaces and hyphens removed, e.g. `G-ABCD` as `GABCD`
+/// 5. the airline designator translated between IATA and ICAO, e.g. `AF1234` as `AFR1234`
+pub struct CallsignMatcher {
+    aliases: HashMap<String, String>,
+    /// The `BAW*` style aliases with the `*` removed, longest prefix first
+    prefix_aliases: Vec<(String, String)>,
+    airlines: HashMap<String, String>,
+    /// ICAO to IATA airline designators
+    airlines_by_icao: HashMap<String, String>,
+    override_file: String,
+    override_file_modified: Option<SystemTime>,
+    overrides: HashMap<String, String>,
+    /// Overrides made while running, which take precedence over the file
+    runtime_overrides: HashMap<String, String>,
+}
+
+/// Which of the [`CallsignMatcher`] rules matched an aircraft.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchRule {
+    Override,
+    Alias,
+    Exact,
+    Normalised,
+    Airline,
+}
+
+/// A simulator aircraft which couldn't be matched to the network, with every callsign that was tried.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmatchedAircraft {
+    pub callsign: String,
+    pub tried: Vec<String>,
+}
+
+impl CallsignMatcher {
+    pub fn new(config: &MatcherConfig) -> CallsignMatcher {
+        let (prefix_aliases, aliases): (Vec<_>, Vec<_>) = config.aliases.iter()
+            .map(|(from, to)| (from.to_uppercase(), to.to_uppercase()))
+            .partition(|(from, _)| from.ends_with('*'));
+        let mut prefix_aliases: Vec<(String, String)> = prefix_aliases.into_iter()
+            .map(|(from, to)| (from.trim_end_matches('*').to_owned(), to.trim_end_matches('*').to_owned()))
+            .collect();
+        prefix_aliases.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+        let mut config_airlines: Vec<(String, String)> = config.airlines.iter().map(|(iata, icao)| (iata.to_uppercase(), icao.to_uppercase())).collect();
+        config_airlines.sort();
+        let airlines: HashMap<String, String> = AIRLINE_DESIGNATORS.iter().map(|(iata, icao)| (iata.to_string(), icao.to_string()))
+            .chain(config_airlines.iter().cloned())
+            .collect();
+        // Where several IATA designators give the same ICAO one, the config wins over the built-in table,
+        // then the alphabetically first designator
+        let mut airlines_by_icao = HashMap::new();
+        for (iata, icao) in config_airlines.iter().cloned().chain(AIRLINE_DESIGNATORS.iter().map(|(iata, icao)| (iata.to_string(), icao.to_string()))) {
+            if airlines.get(&iata) == Some(&icao) {
+                airlines_by_icao.entry(icao).or_insert(iata);
+            }
+        }
+
+        let mut matcher = CallsignMatcher {
+            aliases: aliases.into_iter().collect(),
+            prefix_aliases,
+            airlines,
+            airlines_by_icao,
+            override_file: config.override_file.clone(),
+            override_file_modified: None,
+            overrides: HashMap::new(),
+            runtime_overrides: HashMap::new(),
+        };
+        matcher.reload_overrides();
+        matcher
+    }
+
+    /// Re-reads the override file if it has changed, so that it can be edited while running.
+    pub fn reload_overrides(&mut self) {
+        let modified = fs::metadata(&self.override_file).and_then(|metadata| metadata.modified()).ok();
+        if modified == self.override_file_modified {
+            return;
+        }
+        self.override_file_modified = modified;
+        self.overrides = fs::read_to_string(&self.override_file).map(|contents| parse_overrides(&contents)).unwrap_or_default();
+        if modified.is_some() {
+            println!("Loaded {} callsign overrides from {}", self.overrides.len(), self.override_file);
+        }
+    }
+
+    /// Matches a simulator callsign to a network callsign until Traffic Viewer exits, or with `None`,
+    /// removes such a match.
+    pub fn set_override(&mut self, sim_callsign: &str, network_callsign: Option<&str>) {
+        match network_callsign {
+            Some(network_callsign) => self.runtime_overrides.insert(normalise(sim_callsign), network_callsign.to_uppercase()),
+            None => self.runtime_overrides.remove(&normalise(sim_callsign)),
+        };
+    }
+
+    /// Returns the network callsign for a simulator callsign, using `is_known` to ask whether the network has
+    /// a pilot by that name. On failure, returns the callsigns that were tried.
+    pub fn resolve(&self, callsign: &str, is_known: impl Fn(&str) -> bool) -> Result<(String, MatchRule), UnmatchedAircraft> {
+        let mut tried: Vec<String> = vec![];
+        for (candidate, rule) in self.candidates(callsign) {
+            if tried.contains(&candidate) {
+                continue;
+            }
+            if is_known(&candidate) {
+                return Ok((candidate, rule));
+            }
+            tried.push(candidate);
+        }
+        Err(UnmatchedAircraft { callsign: callsign.to_owned(), tried })
+    }
+
+    fn candidates(&self, callsign: &str) -> Vec<(String, MatchRule)> {
+        let normalised = normalise(callsign);
+        let mut candidates = vec![];
+        if let Some(target) = self.runtime_overrides.get(&normalised).or_else(|| self.overrides.get(&normalised)) {
+            candidates.push((target.clone(), MatchRule::Override));
+        }
+        if let Some(target) = self.alias(&normalised) {
+            candidates.push((target, MatchRule::Alias));
+        }
+        candidates.push((callsign.to_owned(), MatchRule::Exact));
+        candidates.push((normalised.clone(), MatchRule::Normalised));
+        if let Some(translated) = self.translate_airline(&normalised) {
+            candidates.push((translated, MatchRule::Airline));
+        }
+        candidates
+    }
+
+    fn alias(&self, callsign: &str) -> Option<String> {
+        if let Some(target) = self.aliases.get(callsign) {
+            return Some(target.clone());
+        }
+        self.prefix_aliases.iter().find_map(|(from, to)| {
+            let rest = callsign.strip_prefix(from.as_str())?;
+            Some(format!("{}{}", to, rest))
+        })
+    }
+
+    /// Swaps an IATA designator for the ICAO one or vice versa, provided the rest is a flight number.
+    fn translate_airline(&self, callsign: &str) -> Option<String> {
+        let is_flight_number = |rest: &str| !rest.is_empty() && rest.bytes().next().is_some_and(|b| b.is_ascii_digit());
+        if let Some((designator, rest)) = callsign.split_at_checked(3).filter(|(_, rest)| is_flight_number(rest)) {
+            if let Some(iata) = self.airlines_by_icao.get(designator) {
+                return Some(format!("{}{}", iata, rest));
+            }
+        }
+        let (designator, rest) = callsign.split_at_checked(2).filter(|(_, rest)| is_flight_number(rest))?;
+        self.airlines.get(designator).map(|icao| format!("{}{}", icao, rest))
+    }
+}
+
+fn normalise(callsign: &str) -> String {
+    callsign.chars().filter(|c| !c.is_whitespace() && *c != '-').flat_map(char::to_uppercase).collect()
+}
+
+/// Parses the override file: one `SIMULATOR_CALLSIGN NETWORK_CALLSIGN` pair per line, with `#` comments.
+fn parse_overrides(contents: &str) -> HashMap<String, String> {
+    contents.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            Some((normalise(words.next()?), words.next()?.to_uppercase()))
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(aliases: &[(&str, &str)], airlines: &[(&str, &str)]) -> CallsignMatcher {
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect();
+        CallsignMatcher::new(&MatcherConfig {
+            aliases: pairs(aliases),
+            airlines: pairs(airlines),
+            override_file: String::from("tests/fixtures/no-such-overrides.txt"),
+        })
+    }
+
+    #[test]
+    fn longest_alias_prefix_wins() {
+        let matcher = matcher(&[("B*", "X*"), ("BAW*", "SHT*"), ("BA*", "BAW*"), ("BAW1", "SHT9")], &[]);
+        assert_eq!(matcher.alias("BAW123").as_deref(), Some("SHT123"));
+        assert_eq!(matcher.alias("BA123").as_deref(), Some("BAW123"));
+        assert_eq!(matcher.alias("BCS1").as_deref(), Some("XCS1"));
+        assert_eq!(matcher.alias("BAW1").as_deref(), Some("SHT9"));
+        assert_eq!(matcher.alias("DLH1"), None);
+    }
+
+    #[test]
+    fn translates_airline_designators_both_ways() {
+        let matcher = matcher(&[], &[("ZB", "MON"), ("XB", "BAW"), ("WB", "BAW")]);
+        assert_eq!(matcher.translate_airline("AF1234").as_deref(), Some("AFR1234"));
+        assert_eq!(matcher.translate_airline("AFR1234").as_deref(), Some("AF1234"));
+        assert_eq!(matcher.translate_airline("MON12").as_deref(), Some("ZB12"));
+        // The config wins over the built-in table, then the alphabetically first designator
+        assert_eq!(matcher.translate_airline("BAW12").as_deref(), Some("WB12"));
+        assert_eq!(matcher.translate_airline("AFRA"), None);
+    }
+}
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{ivao::IvaoProvider, vatsim::{Atis, Controller, Details, Facility, VatsimProvider}};
+
+
+
+/// The online network whose data is used to enrich the simulator's traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Network {
+    #[default]
+    Vatsim,
+    Ivao,
+}
+impl Network {
+    pub fn name(self) -> &'static str {
+        match self {
+            Network::Vatsim => "VATSIM",
+            Network::Ivao => "IVAO",
+        }
+    }
+
+    pub fn provider(self) -> Box<dyn NetworkProvider> {
+        match self {
+            Network::Vatsim => Box::new(VatsimProvider::default()),
+            Network::Ivao => Box::new(IvaoProvider::default()),
+        }
+    }
+}
+
+
+/// A source of online network data. Each provider downloads its network's own feed format and maps
+/// it into the common model, which is the one used by VATSIM's v3 data feed.
+pub trait NetworkProvider: Send {
+    /// The network's name, for log messages.
+    fn name(&self) -> &'static str;
+
+    /// The URL of the network's data feed.
+    fn data_url(&self) -> &'static str;
+
+    /// Parses a complete data feed document. Returns `None` if the document is the same one as last time.
+    fn parse(&mut self, body: &str) -> Result<Option<NetworkData>, String>;
+}
+
+
+/// Everything parsed from one download of a network's data feed.
+#[derive(Debug, Default, Clone)]
+pub struct NetworkData {
+    pub pilots: Vec<Details>,
+    /// Flight plans filed by pilots who haven't connected yet, with [`Details::prefiled`] set
+    pub prefiles: Vec<Details>,
+    pub controllers: Vec<Controller>,
+    pub atis: Vec<Atis>,
+    /// The controller facility types, if the network publishes them
+    pub facilities: Vec<Facility>,
+}
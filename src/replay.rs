@@ -0,0 +1,189 @@
+use std::{fs, io::{BufRead, BufReader, Write}, net::TcpStream, path::Path, sync::mpsc, thread, time::{Duration, Instant}};
+
+use crate::{capture::{self, Direction}, config::{CaptureConfig, ServerConfig}, network::Network, server::Server};
+
+/// How long the server is given to respond to each replayed line
+const LINE_SETTLE_TIME: Duration = Duration::from_millis(50);
+/// How long the server is given to finish after the last line
+const FINAL_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+
+
+/// Replays the client side of a protocol capture against a fresh [`Server`], and compares what the server
+/// sends back with a golden transcript. With `update_golden`, the golden transcript is instead written (or
+/// overwritten) from this run; without it, a missing golden transcript is an error.
+///
+/// Only the server itself is exercised, i.e. the login handshake, relaying, pings and errors, not the app's
+/// traffic. Fields which legitimately differ between runs, like the handshake key, are ignored.
+///
+/// Returns whether the output matched.
+pub fn run(capture_path: &Path, golden_path: &Path, update_golden: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    if !update_golden && !golden_path.exists() {
+        return Err(format!("There's no golden transcript at {}, run with --update-golden to create it", golden_path.display()).into());
+    }
+    let inbound: Vec<String> = capture::read(capture_path)?.into_iter()
+        .filter(|captured| captured.direction == Direction::Inbound)
+        .map(|captured| captured.line)
+        .collect();
+    println!("Replaying {} client lines from {}", inbound.len(), capture_path.display());
+
+    let config = ServerConfig {
+        bind_address: String::from("127.0.0.1:0"),
+        ..ServerConfig::default()
+    };
+    let mut server = Server::start(&config, &CaptureConfig::default(), Network::default())?;
+    let mut client = TcpStream::connect(server.local_addr())?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut reader = BufReader::new(client.try_clone()?);
+    thread::Builder::new().name(String::from("TrafficViewerReplayThread")).spawn(move|| {
+        let mut buffer = vec![];
+        while let Ok(n) = reader.read_until(b'\n', &mut buffer) {
+            if n == 0 || tx.send(String::from_utf8_lossy(&buffer).trim().to_owned()).is_err() {
+                break;
+            }
+            buffer.clear();
+        }
+    })?;
+
+    settle(&mut server, LINE_SETTLE_TIME);
+    for line in inbound.iter() {
+        client.write_all(format!("{line}\r\n").as_bytes())?;
+        settle(&mut server, LINE_SETTLE_TIME);
+    }
+    settle(&mut server, FINAL_SETTLE_TIME);
+    client.shutdown(std::net::Shutdown::Both).ok();
+    drop(server);
+
+    let actual: Vec<String> = rx.try_iter().collect();
+    if update_golden {
+        let transcript: String = actual.iter().map(|line| format!("0 > {}\n", line)).collect();
+        fs::write(golden_path, transcript)?;
+        println!("Wrote golden transcript of {} lines to {}", actual.len(), golden_path.display());
+        return Ok(true);
+    }
+
+    let expected: Vec<String> = capture::read(golden_path)?.into_iter()
+        .filter(|captured| captured.direction == Direction::Outbound)
+        .map(|captured| captured.line)
+        .collect();
+    let expected: Vec<String> = expected.iter().map(|line| normalise(line)).collect();
+    let actual: Vec<String> = actual.iter().map(|line| normalise(line)).collect();
+    if expected == actual {
+        println!("Output matches {} ({} lines)", golden_path.display(), actual.len());
+        return Ok(true);
+    }
+    println!("Output differs from {}:", golden_path.display());
+    for line in diff(&expected, &actual) {
+        println!("{}", line);
+    }
+    Ok(false)
+}
+
+fn settle(server: &mut Server, duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        server.poll();
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Blanks out the fields which change from one run to the next.
+fn normalise(line: &str) -> String {
+    let mut fields: Vec<&str> = line.split(':').collect();
+    // $DI: server version and handshake key
+    if line.starts_with("$DI") && fields.len() >= 4 {
+        fields[2] = "*";
+        fields[3] = "*";
+    }
+    // Server pings carry the time they were sent
+    if line.starts_with("$PISERVER") && fields.len() >= 3 {
+        fields[2] = "*";
+    }
+    fields.join(":")
+}
+
+/// A line diff of two transcripts, with `-` for expected lines which are missing and `+` for unexpected ones.
+fn diff(expected: &[String], actual: &[String]) -> Vec<String> {
+    // Longest common subsequence, filled in from the end
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = match expected[i] == actual[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+        } else if j < actual.len() && (i == expected.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        } else {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        }
+    }
+    lines
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn ignores_fields_which_change_between_runs() {
+        assert_eq!(normalise("$DISERVER:CLIENT:Traffic Viewer 0.1.0:1a2b3c4d"), "$DISERVER:CLIENT:*:*");
+        assert_eq!(normalise("$PISERVER:EGLL_TWR:1792318800"), "$PISERVER:EGLL_TWR:*");
+        assert_eq!(normalise("$POSERVER:EGLL_TWR:12345"), "$POSERVER:EGLL_TWR:12345");
+    }
+
+    #[test]
+    fn reports_changed_lines() {
+        let expected = lines(&["$DISERVER:CLIENT:*:*", "$POSERVER:EGLL_TWR:12345", "#TMSERVER:EGLL_TWR:Welcome"]);
+        assert!(diff(&expected, &expected).is_empty());
+        let actual = lines(&["$DISERVER:CLIENT:*:*", "$POSERVER:EGLL_TWR:54321", "#TMSERVER:EGLL_TWR:Welcome", "$ERSERVER:EGLL_TWR:003::Already registered"]);
+        assert_eq!(diff(&expected, &actual), [
+            "+ $POSERVER:EGLL_TWR:54321",
+            "- $POSERVER:EGLL_TWR:12345",
+            "+ $ERSERVER:EGLL_TWR:003::Already registered",
+        ]);
+        assert_eq!(diff(&expected, &expected[..1]), ["- $POSERVER:EGLL_TWR:12345", "- #TMSERVER:EGLL_TWR:Welcome"]);
+    }
+
+    #[test]
+    fn replays_against_a_golden_transcript() {
+        let directory = std::env::temp_dir().join(format!("traffic-viewer-replay-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let (capture_path, golden_path) = (directory.join("capture.log"), directory.join("golden.log"));
+        fs::write(&capture_path, [
+            "1792318800000 < $IDEGLL_TWR:SERVER:de1c:EuroScope 3.2:3:2:1234567:abcdef12",
+            "1792318800010 > $DISERVER:CLIENT:Traffic Viewer 0.1.0:1a2b3c4d",
+            "1792318800020 < #AAEGLL_TWR:SERVER:Sam Controller:1234567:********:3:100",
+            "1792318800030 < $PIEGLL_TWR:SERVER:12345",
+        ].join("\n")).unwrap();
+
+        // A golden transcript is only written when asked for
+        assert!(run(&capture_path, &golden_path, false).is_err());
+        assert!(run(&capture_path, &golden_path, true).unwrap());
+        let golden = fs::read_to_string(&golden_path).unwrap();
+        assert!(golden.contains("0 > $POSERVER:EGLL_TWR:12345\n"), "{}", golden);
+
+        // The handshake key and ping times differ from run to run, but the rest has to match
+        assert!(run(&capture_path, &golden_path, false).unwrap());
+        fs::write(&golden_path, golden.replace("$POSERVER:EGLL_TWR:12345", "$POSERVER:EGLL_TWR:54321")).unwrap();
+        assert!(!run(&capture_path, &golden_path, false).unwrap());
+        fs::remove_dir_all(&directory).ok();
+    }
+}
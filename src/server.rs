@@ -1,60 +1,800 @@
-use std::{io::{BufRead, BufReader, LineWriter, Write}, net::TcpStream, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, Sender}, Arc}, thread::{self, JoinHandle}, time::Duration};
+use std::{collections::{HashMap, VecDeque}, io::{BufRead, BufReader, LineWriter, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}, Arc, Condvar, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use fsd_interface::FsdMessageType;
+use fsd_interface::{errors::FsdError, messages::{AtcDeregisterMessage, AtcRegisterMessage, FsdErrorMessage, InitialServerHandshakeMessage, PingMessage, PongMessage, AIRCRAFT_HANDLER_RECIPIENT}, AtcRating, ClientQueryType, FsdMessageType, ProtocolRevision};
+use serde::Serialize;
 
+use crate::{auth::{AccessLevel, Authenticator}, capture::{Direction, ProtocolCapture}, config::{CaptureConfig, ServerConfig}, encoding::{Codec, Encoding}, network::Network, worker::unix_timestamp};
 
+const SERVER_CALLSIGN: &str = "SERVER";
+const SERVER_VERSION: &str = concat!("Traffic Viewer ", env!("CARGO_PKG_VERSION"));
+const MAX_CALLSIGN_LENGTH: usize = 12;
+/// How often logged-in clients are pinged
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a client may go without sending anything before it's disconnected. Clients send position
+/// updates every few seconds and answer pings, so a live one is never this quiet
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// How many writes in a row may fail before a client is assumed to have gone
+const MAX_WRITE_FAILURES: u32 = 3;
+/// How long a closing session's writer keeps writing what was already queued before giving up on it
+const CLOSING_FLUSH_DEADLINE: Duration = Duration::from_secs(1);
 
 
+
+
+/// The FSD server which ATC clients connect to.
+///
+/// Connections are accepted on a background thread. Each one then has to log in, by identifying
+/// itself in reply to the server's `$DI` and registering as a controller with `#AA`, before any of
+/// its packets are passed on by [`Server::poll`]. Connections and logins are checked by an [`Authenticator`].
+///
+/// Packets which logged-in clients address to one another, such as handoffs, scratchpad updates and
+/// private messages, are relayed between the sessions as a real FSD server would.
+///
+/// Logged-in clients are pinged periodically, and sessions which go quiet or can't be written to are closed.
+/// Each session writes on its own thread, so a slow client can't hold up the others or the simulator polling.
 pub struct Server {
-    recv_thread: Option<JoinHandle<()>>,
-    writer: LineWriter<TcpStream>,
+    listener_thread: Option<JoinHandle<()>>,
     should_stop: Arc<AtomicBool>,
-    receiver: Receiver<FsdMessageType>,
+    incoming: Receiver<(TcpStream, SocketAddr)>,
+    sessions: Vec<Session>,
+    authenticator: Authenticator,
+    limits: OutboundLimits,
+    capture: CaptureConfig,
+    local_addr: SocketAddr,
+    /// The network for sessions whose account doesn't choose one
+    default_network: Network,
 }
 impl Server {
-    pub fn new(tcp_stream: TcpStream) -> Server {
-        tcp_stream.set_write_timeout(Some(Duration::from_secs(2))).ok();
+    pub fn start(config: &ServerConfig, capture: &CaptureConfig, default_network: Network) -> Result<Server, Box<dyn std::error::Error>> {
+        let authenticator = Authenticator::new(config)?;
+        let listener = TcpListener::bind(&config.bind_address)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
         let (tx, rx) = mpsc::channel();
         let should_stop = Arc::new(AtomicBool::new(false));
-        let recv_thread = Some(recv_thread(Arc::clone(&should_stop), tcp_stream.try_clone().unwrap(), tx));
-        Server {
-            recv_thread,
-            writer: LineWriter::new(tcp_stream),
+        println!("Waiting for ATC client connections on {}", config.bind_address);
+        Ok(Server {
+            listener_thread: Some(listener_thread(Arc::clone(&should_stop), listener, tx)),
             should_stop,
-            receiver: rx,
-        }
+            incoming: rx,
+            sessions: vec![],
+            authenticator,
+            limits: OutboundLimits {
+                queue_capacity: config.send_queue_capacity,
+                max_packets_per_second: config.max_packets_per_second,
+                encoding: config.encoding,
+            },
+            capture: capture.clone(),
+            local_addr,
+            default_network,
+        })
     }
 
+    /// Accepts any new connections, advances each session's login, and returns the packets received
+    /// from logged-in clients. A client logging off or disconnecting is reported as an `AtcDeregisterMessage`.
     pub fn poll(&mut self) -> Vec<FsdMessageType> {
+        while let Ok((mut tcp_stream, address)) = self.incoming.try_recv() {
+            if !self.authenticator.allows_address(address.ip()) {
+                println!("Refused connection from {}, which is not in an allowed network", address);
+                tcp_stream.write_all(format!("{}\r\n", FsdErrorMessage::new(SERVER_CALLSIGN, "CLIENT", FsdError::UnauthorisedClient)).as_bytes()).ok();
+                continue;
+            }
+            println!("Incoming connection from {}", address);
+            let capture = ProtocolCapture::start(&self.capture, &address.to_string());
+            self.sessions.push(Session::new(tcp_stream, address, &self.limits, capture));
+        }
+
         let mut vec = vec![];
-        while let Ok(msg) = self.receiver.try_recv() {
-            vec.push(msg);
+        for index in 0..self.sessions.len() {
+            let lines = self.sessions[index].receive();
+            for line in lines {
+                if let Some(message) = self.handle_line(index, &line) {
+                    vec.push(message);
+                }
+            }
+            self.sessions[index].check_liveness();
+        }
+
+        let mut disconnected = vec![];
+        self.sessions.retain_mut(|session| {
+            if session.is_connected() {
+                return true;
+            }
+            if let LoginState::LoggedIn { callsign, cid, .. } = &session.state {
+                println!("{} disconnected", callsign);
+                disconnected.push(AtcDeregisterMessage::new(callsign, cid));
+            }
+            false
+        });
+        // Let the remaining clients know about any which disconnected without logging off
+        for msg in disconnected {
+            self.broadcast(&msg.to_string());
+            vec.push(FsdMessageType::AtcDeregisterMessage(msg));
+        }
+        vec
+    }
+
+    /// Returns the callsigns of the logged-in clients, in the order they logged in.
+    pub fn callsigns(&self) -> Vec<String> {
+        self.sessions.iter().filter_map(|session| session.callsign().map(str::to_owned)).collect()
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the connection statistics of every session, including those which haven't logged in yet.
+    pub fn stats(&self) -> Vec<SessionStats> {
+        self.sessions.iter().map(Session::stats).collect()
+    }
+
+    /// Returns whether any clients have logged in. Connections which haven't, e.g. port probes, don't count.
+    pub fn has_sessions(&self) -> bool {
+        self.sessions.iter().any(|session| session.callsign().is_some())
+    }
+
+    /// Returns the callsigns of the logged-in clients using a network's data, in the order they logged in.
+    pub fn callsigns_on(&self, network: Network) -> Vec<String> {
+        self.sessions.iter()
+            .filter(|session| session.network() == Some(network))
+            .filter_map(|session| session.callsign().map(str::to_owned))
+            .collect()
+    }
+
+    /// The network whose data the client logged in as `callsign` is given.
+    pub fn network_of(&self, callsign: &str) -> Option<Network> {
+        self.sessions.iter().find(|session| session.callsign().is_some_and(|cs| cs.eq_ignore_ascii_case(callsign))).and_then(Session::network)
+    }
+
+    /// Whether the client logged in as `callsign` has an observer account.
+    pub fn is_observer(&self, callsign: &str) -> bool {
+        self.sessions.iter().any(|session| session.callsign().is_some_and(|cs| cs.eq_ignore_ascii_case(callsign)) && matches!(session.state, LoginState::LoggedIn { access: AccessLevel::Observer, .. }))
+    }
+
+    /// Sends a packet to the client logged in as `callsign`. Returns false if there is no such client
+    /// or the write failed.
+    pub fn send_to(&mut self, callsign: &str, message: &str) -> bool {
+        match self.sessions.iter_mut().find(|session| session.callsign().is_some_and(|cs| cs.eq_ignore_ascii_case(callsign))) {
+            Some(session) => session.send_packet(message),
+            None => false,
+        }
+    }
+
+    /// Sends a packet to every logged-in client.
+    pub fn broadcast(&mut self, message: &str) {
+        for session in self.sessions.iter_mut().filter(|session| session.callsign().is_some()) {
+            session.send_packet(message);
+        }
+    }
+
+    /// Sends a packet to every logged-in client using a network's data.
+    pub fn broadcast_to_network(&mut self, network: Network, message: &str) {
+        for session in self.sessions.iter_mut().filter(|session| session.network() == Some(network)) {
+            session.send_packet(message);
+        }
+    }
+
+    /// Sends a packet to every logged-in client other than `except`.
+    pub fn broadcast_except(&mut self, except: &str, message: &str) {
+        for session in self.sessions.iter_mut().filter(|session| session.callsign().is_some_and(|callsign| !callsign.eq_ignore_ascii_case(except))) {
+            session.send_packet(message);
+        }
+    }
+
+    /// Sends every logged-in client its own copy of a packet which is addressed to it.
+    pub fn broadcast_to_each(&mut self, message: impl Fn(&str) -> String) {
+        for session in self.sessions.iter_mut() {
+            if let Some(callsign) = session.callsign().map(str::to_owned) {
+                session.send_packet(&message(&callsign));
+            }
+        }
+    }
+
+    /// Sends every logged-in client using a network's data its own copy of a packet which is addressed to it.
+    pub fn broadcast_to_each_on_network(&mut self, network: Network, message: impl Fn(&str) -> String) {
+        for session in self.sessions.iter_mut().filter(|session| session.network() == Some(network)) {
+            if let Some(callsign) = session.callsign().map(str::to_owned) {
+                session.send_packet(&message(&callsign));
+            }
+        }
+    }
+
+    fn handle_line(&mut self, index: usize, line: &str) -> Option<FsdMessageType> {
+        let message = fsd_interface::parse_message(line);
+        let session = &self.sessions[index];
+        match (&session.state, message) {
+            (LoginState::LoggedIn { .. }, Ok(FsdMessageType::AtcRegisterMessage(msg))) => {
+                self.reject(index, &msg.from, FsdError::AlreadyRegistered, false);
+                None
+            },
+            (LoginState::LoggedIn { callsign, .. }, Ok(FsdMessageType::AtcDeregisterMessage(msg))) => {
+                println!("{} logged off", callsign);
+                self.relay(index, line);
+                self.sessions[index].state = LoginState::LoggedOff;
+                self.sessions[index].close();
+                Some(FsdMessageType::AtcDeregisterMessage(msg))
+            },
+            (LoginState::LoggedIn { access: AccessLevel::Observer, callsign, .. }, Ok(message)) if !is_read_only(&message) => {
+                println!("Ignored packet from observer {}: {}", callsign, line);
+                None
+            },
+            (LoginState::LoggedIn { .. }, Ok(FsdMessageType::PingMessage(msg))) if msg.to.eq_ignore_ascii_case(SERVER_CALLSIGN) => {
+                self.sessions[index].send_packet(&PongMessage::new(SERVER_CALLSIGN, &msg.from, msg.timestamp).to_string());
+                None
+            },
+            (LoginState::LoggedIn { .. }, Ok(FsdMessageType::PongMessage(msg))) if msg.to.eq_ignore_ascii_case(SERVER_CALLSIGN) => {
+                let session = &mut self.sessions[index];
+                if let Some((timestamp, sent)) = session.ping_sent {
+                    if timestamp == msg.timestamp {
+                        session.round_trip = Some(sent.elapsed());
+                    }
+                }
+                None
+            },
+            (LoginState::LoggedIn { .. }, Ok(message)) => {
+                if let FsdMessageType::AtcPositionUpdateMessage(_) = message {
+                    self.sessions[index].last_position = Some(line.to_owned());
+                }
+                self.relay(index, line);
+                Some(message)
+            },
+            // Packets which we can't parse may still mean something to the other clients
+            (LoginState::LoggedIn { access: AccessLevel::Controller, .. }, Err(_)) => {
+                self.relay(index, line);
+                None
+            },
+            (LoginState::LoggedIn { .. }, Err(_)) => None,
+
+            (LoginState::AwaitingIdentification, Ok(FsdMessageType::InitialClientHandshakeMessage(msg))) => {
+                println!("Client identified as {} {}.{}", msg.client_name, msg.major_version, msg.minor_version);
+                self.sessions[index].state = LoginState::AwaitingRegistration { identified: true };
+                None
+            },
+            (LoginState::AwaitingIdentification | LoginState::AwaitingRegistration { .. }, Ok(FsdMessageType::AtcRegisterMessage(msg))) => {
+                let identified = matches!(session.state, LoginState::AwaitingRegistration { identified: true });
+                match self.validate_registration(&msg, identified) {
+                    Ok(access) => {
+                        println!("{} logged in from {} as CID {}{}", msg.from, self.sessions[index].address, msg.cid, if access == AccessLevel::Observer { " (observer)" } else { "" });
+                        let network = self.authenticator.network(&msg.cid).unwrap_or(self.default_network);
+                        self.sessions[index].state = LoginState::LoggedIn { callsign: msg.from.clone(), cid: msg.cid.clone(), access, network };
+                        self.sessions[index].registration = Some(line.to_owned());
+                        self.introduce(index);
+                        Some(FsdMessageType::AtcRegisterMessage(msg))
+                    },
+                    Err(error) => {
+                        self.reject(index, &msg.from, error, true);
+                        None
+                    },
+                }
+            },
+            // A registration which couldn't be parsed is most likely from a protocol revision we don't know
+            (LoginState::AwaitingIdentification | LoginState::AwaitingRegistration { .. }, Err(_)) if line.starts_with("#AA") => {
+                let callsign = line[3..].split(':').next().unwrap_or_default().to_owned();
+                let revision = line.split(':').nth(6).unwrap_or_default();
+                let error = match revision.parse::<ProtocolRevision>() {
+                    Ok(_) => FsdError::SyntaxError,
+                    Err(_) => FsdError::InvalidProtocolRevision,
+                };
+                self.reject(index, &callsign, error, true);
+                None
+            },
+            // Nothing else is accepted until the client has logged in
+            _ => None,
+        }
+    }
+
+    /// Introduces a newly logged-in session and the clients already logged in to each other.
+    fn introduce(&mut self, index: usize) {
+        let existing: Vec<String> = self.sessions.iter().enumerate()
+            .filter(|(other, session)| *other != index && session.callsign().is_some())
+            .flat_map(|(_, session)| session.registration.iter().chain(session.last_position.iter()).cloned())
+            .collect();
+        for packet in existing {
+            self.sessions[index].send_packet(&packet);
+        }
+        if let Some(registration) = self.sessions[index].registration.clone() {
+            self.relay(index, &registration);
+        }
+    }
+
+    /// Passes a packet from a logged-in session on to whichever other sessions it's addressed to.
+    fn relay(&mut self, from: usize, line: &str) {
+        let recipients = recipients(line);
+        if let Recipients::Nobody = recipients {
+            return;
+        }
+        for (index, session) in self.sessions.iter_mut().enumerate() {
+            if index == from || session.callsign().is_none() {
+                continue;
+            }
+            let addressed = match recipients {
+                Recipients::Nobody => false,
+                Recipients::Everyone => true,
+                Recipients::Frequency(frequency) => session.frequency() == Some(frequency),
+                Recipients::Callsign(callsign) => session.callsign().is_some_and(|cs| cs.eq_ignore_ascii_case(callsign)),
+            };
+            if addressed {
+                session.send_packet(line);
+            }
         }
-        return vec;
     }
 
-    pub fn send_packet(&mut self, message: &str) -> bool {
-        match self.writer.write(&string_to_byte_slice(&format!("{message}\r\n"))) {
-            Ok(0) | Err(_) => return false,
-            Ok(_) => return true,
+    fn validate_registration(&self, msg: &AtcRegisterMessage, identified: bool) -> Result<AccessLevel, FsdError> {
+        // Only classic clients may skip identifying themselves
+        if !identified && msg.protocol != ProtocolRevision::Classic {
+            return Err(FsdError::UnauthorisedClient);
         }
+        if !is_valid_callsign(&msg.from) {
+            return Err(FsdError::InvalidCallsign);
+        }
+        if self.sessions.iter().any(|session| session.callsign().is_some_and(|callsign| callsign.eq_ignore_ascii_case(&msg.from))) {
+            return Err(FsdError::CallsignInUse);
+        }
+        if matches!(msg.rating, AtcRating::Supervisor | AtcRating::Administrator) {
+            return Err(FsdError::RequestedLevelTooHigh);
+        }
+        self.authenticator.authenticate(msg)
     }
 
+    /// Sends an FSD error to a session, closing it if the error is fatal.
+    fn reject(&mut self, index: usize, callsign: &str, error: FsdError, fatal: bool) {
+        let session = &mut self.sessions[index];
+        println!("Rejected {} from {}: {}", callsign, session.address, error);
+        session.send_packet(&FsdErrorMessage::new(SERVER_CALLSIGN, callsign, error).to_string());
+        if fatal {
+            session.close();
+        }
+    }
 }
 impl Drop for Server {
     fn drop(&mut self) {
         self.should_stop.store(true, Ordering::Relaxed);
-        if let Some(thread) = self.recv_thread.take() {
+        if let Some(thread) = self.listener_thread.take() {
             thread.join().ok();
         }
     }
 }
 
 
-fn recv_thread(should_stop: Arc<AtomicBool>, tcp_stream: TcpStream, sender: Sender<FsdMessageType>) -> JoinHandle<()> {
+/// Where a session has got to in logging in.
+enum LoginState {
+    /// The server has sent `$DI` and is waiting for the client's `$ID`
+    AwaitingIdentification,
+    /// Waiting for the client to register with `#AA`. Classic clients may register without identifying
+    AwaitingRegistration { identified: bool },
+    LoggedIn { callsign: String, cid: String, access: AccessLevel, network: Network },
+    /// The client has sent `#DA` and the connection is closing
+    LoggedOff,
+}
+
+/// A single client connection.
+struct Session {
+    outbound: Arc<Outbound>,
+    should_stop: Arc<AtomicBool>,
+    connected: Arc<AtomicBool>,
+    receiver: Receiver<Vec<u8>>,
+    /// Shared with the writer thread, as the encoding may only be settled by what the client sends
+    codec: Arc<Mutex<Codec>>,
+    /// Shared with the writer thread, which records the lines as they're actually written
+    capture: Option<Arc<Mutex<ProtocolCapture>>>,
+    address: SocketAddr,
+    state: LoginState,
+    /// The client's `#AA` packet, for introducing it to clients which log in later
+    registration: Option<String>,
+    /// The client's latest `%` packet, likewise
+    last_position: Option<String>,
+    connected_at: u64,
+    last_activity: Instant,
+    /// The timestamp of the latest server ping, and when it was sent
+    ping_sent: Option<(u64, Instant)>,
+    round_trip: Option<Duration>,
+    bytes_in: u64,
+    packets_in: u64,
+}
+impl Session {
+    fn new(tcp_stream: TcpStream, address: SocketAddr, limits: &OutboundLimits, capture: Option<ProtocolCapture>) -> Session {
+        tcp_stream.set_write_timeout(Some(Duration::from_secs(2))).ok();
+        let (tx, rx) = mpsc::channel();
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let connected = Arc::new(AtomicBool::new(true));
+        let outbound = Arc::new(Outbound::new(limits.queue_capacity, limits.max_packets_per_second));
+        let codec = Arc::new(Mutex::new(Codec::new(limits.encoding)));
+        let capture = capture.map(|capture| Arc::new(Mutex::new(capture)));
+        // The threads aren't joined: the writer shuts the socket down when it finishes, which ends the receiver too
+        recv_thread(Arc::clone(&should_stop), Arc::clone(&connected), tcp_stream.try_clone().unwrap(), tx);
+        writer_thread(Arc::clone(&should_stop), Arc::clone(&connected), tcp_stream, Arc::clone(&outbound), Arc::clone(&codec), capture.clone(), address);
+        let mut session = Session {
+            outbound,
+            should_stop,
+            connected,
+            receiver: rx,
+            codec,
+            capture,
+            address,
+            state: LoginState::AwaitingIdentification,
+            registration: None,
+            last_position: None,
+            connected_at: unix_timestamp(),
+            last_activity: Instant::now(),
+            ping_sent: None,
+            round_trip: None,
+            bytes_in: 0,
+            packets_in: 0,
+        };
+        session.send_packet(&InitialServerHandshakeMessage::new(SERVER_CALLSIGN, "CLIENT", SERVER_VERSION, initial_key()).to_string());
+        session
+    }
+
+    fn receive(&mut self) -> Vec<String> {
+        let mut vec = vec![];
+        while let Ok(bytes) = self.receiver.try_recv() {
+            self.last_activity = Instant::now();
+            self.bytes_in += bytes.len() as u64;
+            self.packets_in += 1;
+            let line = self.codec.lock().unwrap().decode(&bytes);
+            let line = line.trim();
+            if let Some(capture) = &self.capture {
+                capture.lock().unwrap().record(Direction::Inbound, line);
+            }
+            vec.push(line.to_owned());
+        }
+        return vec;
+    }
+
+    /// Closes the session if the client has gone quiet, and pings it if it's due.
+    fn check_liveness(&mut self) {
+        if !self.is_connected() {
+            return;
+        }
+        if self.last_activity.elapsed() > IDLE_TIMEOUT {
+            println!("Closing connection from {}, which has sent nothing for {} seconds", self.address, IDLE_TIMEOUT.as_secs());
+            self.close();
+            return;
+        }
+        let Some(callsign) = self.callsign().map(str::to_owned) else {
+            return;
+        };
+        if self.ping_sent.is_none_or(|(_, sent)| sent.elapsed() >= PING_INTERVAL) {
+            let timestamp = unix_timestamp();
+            self.send_packet(&PingMessage::new(SERVER_CALLSIGN, callsign, timestamp).to_string());
+            self.ping_sent = Some((timestamp, Instant::now()));
+        }
+    }
+
+    fn stats(&self) -> SessionStats {
+        SessionStats {
+            callsign: self.callsign().map(str::to_owned),
+            address: self.address.to_string(),
+            connected_at: self.connected_at,
+            last_activity: unix_timestamp().saturating_sub(self.last_activity.elapsed().as_secs()),
+            round_trip_ms: self.round_trip.map(|round_trip| round_trip.as_millis() as u64),
+            bytes_in: self.bytes_in,
+            bytes_out: self.outbound.bytes_out.load(Ordering::Relaxed),
+            packets_in: self.packets_in,
+            packets_out: self.outbound.packets_out.load(Ordering::Relaxed),
+            packets_queued: self.outbound.queue.lock().unwrap().packets.len(),
+            packets_dropped: self.outbound.dropped.load(Ordering::Relaxed),
+            encoding: self.codec.lock().unwrap().encoding(),
+            network: self.network(),
+        }
+    }
+
+    /// Returns false once the client has closed the connection.
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn callsign(&self) -> Option<&str> {
+        match &self.state {
+            LoginState::LoggedIn { callsign, .. } => Some(callsign),
+            _ => None,
+        }
+    }
+
+    fn network(&self) -> Option<Network> {
+        match &self.state {
+            LoginState::LoggedIn { network, .. } => Some(*network),
+            _ => None,
+        }
+    }
+
+    /// The client's primary frequency in FSD's five digit form, from its latest position update.
+    fn frequency(&self) -> Option<&str> {
+        self.last_position.as_deref()?.split(':').nth(1)
+    }
+
+    /// Queues a packet for the session's writer thread. Returns false if the session has closed.
+    fn send_packet(&mut self, message: &str) -> bool {
+        if !self.is_connected() {
+            return false;
+        }
+        self.outbound.push(message);
+        true
+    }
+
+    /// Closes the session once the packets already queued, such as an error reply, have been written.
+    fn close(&mut self) {
+        self.connected.store(false, Ordering::Relaxed);
+        self.outbound.ready.notify_all();
+    }
+
+}
+impl Drop for Session {
+    fn drop(&mut self) {
+        // The writer finishes on its own within CLOSING_FLUSH_DEADLINE, so a slow client can't hold up the main loop
+        self.should_stop.store(true, Ordering::Relaxed);
+        self.outbound.ready.notify_all();
+    }
+}
+
+
+/// How much a session's writer thread may queue and send.
+struct OutboundLimits {
+    queue_capacity: usize,
+    /// No limit if 0
+    max_packets_per_second: u32,
+    encoding: Encoding,
+}
+
+/// The packets waiting to be written to a client. The queue is bounded: once it's full the oldest packet
+/// is dropped, and a position update replaces any queued one for the same callsign rather than
+/// queueing behind it, so a slow client sees the latest picture instead of falling ever further behind.
+struct Outbound {
+    queue: Mutex<OutboundQueue>,
+    ready: Condvar,
+    capacity: usize,
+    /// No limit if 0
+    max_packets_per_second: u32,
+    dropped: AtomicU64,
+    bytes_out: AtomicU64,
+    packets_out: AtomicU64,
+}
+impl Outbound {
+    fn new(capacity: usize, max_packets_per_second: u32) -> Outbound {
+        Outbound {
+            queue: Mutex::new(OutboundQueue {
+                packets: VecDeque::with_capacity(capacity),
+                first: 0,
+                positions: HashMap::new(),
+            }),
+            ready: Condvar::new(),
+            capacity: capacity.max(1),
+            max_packets_per_second,
+            dropped: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            packets_out: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, packet: &str) {
+        let mut queue = self.queue.lock().unwrap();
+        let key = position_key(packet).map(|(kind, callsign)| (kind, callsign.to_owned()));
+        if let Some(sequence) = key.as_ref().and_then(|key| queue.positions.get(key)) {
+            let index = (sequence - queue.first) as usize;
+            queue.packets[index] = packet.to_owned();
+            return;
+        }
+        if queue.packets.len() >= self.capacity {
+            queue.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        let sequence = queue.first + queue.packets.len() as u64;
+        if let Some(key) = key {
+            queue.positions.insert(key, sequence);
+        }
+        queue.packets.push_back(packet.to_owned());
+        self.ready.notify_one();
+    }
+}
+
+/// The packets of an [`Outbound`], with the queued position update for each callsign indexed so that a
+/// newer one can replace it.
+struct OutboundQueue {
+    packets: VecDeque<String>,
+    /// The sequence number of the packet at the front, counting every packet ever queued
+    first: u64,
+    /// The sequence number of the queued position update for each type and callsign
+    positions: HashMap<(char, String), u64>,
+}
+impl OutboundQueue {
+    fn pop_front(&mut self) -> Option<String> {
+        let packet = self.packets.pop_front()?;
+        if let Some(key) = position_key(&packet).map(|(kind, callsign)| (kind, callsign.to_owned())) {
+            if self.positions.get(&key) == Some(&self.first) {
+                self.positions.remove(&key);
+            }
+        }
+        self.first += 1;
+        Some(packet)
+    }
+}
+
+/// The type and callsign of a position update, which later updates for the same callsign supersede.
+fn position_key(packet: &str) -> Option<(char, &str)> {
+    let kind = packet.chars().next()?;
+    match kind {
+        // @mode:callsign:...
+        '@' => packet.split(':').nth(1).map(|callsign| (kind, callsign)),
+        // %callsign:...
+        '%' => packet[1..].split(':').next().map(|callsign| (kind, callsign)),
+        _ => None,
+    }
+}
+
+
+/// A session's connection statistics, as shown by the API. Timestamps are seconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionStats {
+    /// `None` until the client has logged in
+    pub callsign: Option<String>,
+    pub address: String,
+    pub connected_at: u64,
+    pub last_activity: u64,
+    /// The round trip time of the latest answered server ping
+    pub round_trip_ms: Option<u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    /// Packets waiting to be written
+    pub packets_queued: usize,
+    /// Packets dropped because the queue was full
+    pub packets_dropped: u64,
+    pub encoding: Encoding,
+    /// `None` until the client has logged in
+    pub network: Option<Network>,
+}
+
+fn is_valid_callsign(callsign: &str) -> bool {
+    (2..=MAX_CALLSIGN_LENGTH).contains(&callsign.len()) && callsign.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Who a packet from one client should be relayed to.
+enum Recipients<'a> {
+    Nobody,
+    Everyone,
+    /// The clients whose primary frequency is this one, in FSD's five digit form
+    Frequency(&'a str),
+    Callsign(&'a str),
+}
+
+fn recipients(line: &str) -> Recipients<'_> {
+    // Registrations, logoffs and controller positions are for everyone to see
+    if line.starts_with("#AA") || line.starts_with("#DA") || line.starts_with('%') {
+        return Recipients::Everyone;
+    }
+    // Otherwise only packets which carry a recipient are relayed. Flight plan amendments are shared by
+    // the app, as they're merged with the network's plans first
+    let addressed = ["#TM", "#PC", "$CQ", "$CR", "$HO", "$HA", "$PI", "$PO"];
+    if !addressed.iter().any(|prefix| line.starts_with(prefix)) {
+        return Recipients::Nobody;
+    }
+    match line.split(':').nth(1).unwrap_or_default() {
+        // EuroScope addresses coordination packets meant for every controller to the aircraft handler frequency
+        "*" | "*A" | AIRCRAFT_HANDLER_RECIPIENT => Recipients::Everyone,
+        to if to.eq_ignore_ascii_case(SERVER_CALLSIGN) || to.is_empty() => Recipients::Nobody,
+        to => match to.strip_prefix('@') {
+            Some(frequency) => Recipients::Frequency(frequency),
+            None => Recipients::Callsign(to),
+        },
+    }
+}
+
+/// Whether a packet from an observer can be passed on, because it only asks for information or
+/// concerns the observer itself.
+fn is_read_only(message: &FsdMessageType) -> bool {
+    match message {
+        FsdMessageType::AtcPositionUpdateMessage(_) | FsdMessageType::AtcSecondaryVisCentreMessage(_) | FsdMessageType::MetarRequestMessage(_)
+            | FsdMessageType::PingMessage(_) | FsdMessageType::PongMessage(_) | FsdMessageType::ClientQueryResponseMessage(_)
+            | FsdMessageType::PlaneInfoRequestMessage(_) => true,
+        FsdMessageType::TextMessage(msg) => msg.to.eq_ignore_ascii_case(SERVER_CALLSIGN),
+        FsdMessageType::ClientQueryMessage(msg) => matches!(msg.query_type, ClientQueryType::IsValidATC(_) | ClientQueryType::Capabilities | ClientQueryType::Com1Freq
+            | ClientQueryType::RealName | ClientQueryType::ATIS | ClientQueryType::PublicIP | ClientQueryType::INF | ClientQueryType::FlightPlan(_)
+            | ClientQueryType::WhoHas(_) | ClientQueryType::AircraftConfigurationRequest),
+        _ => false,
+    }
+}
+
+/// A key for the `$DI` packet. Clients only echo it back, so it needn't be cryptographically random.
+fn initial_key() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.subsec_nanos()).unwrap_or_default();
+    format!("{:08x}", nanos.wrapping_mul(2654435761))
+}
+
+
+fn listener_thread(should_stop: Arc<AtomicBool>, listener: TcpListener, sender: Sender<(TcpStream, SocketAddr)>) -> JoinHandle<()> {
+    thread::Builder::new().name(String::from("TrafficViewerListenerThread")).spawn(move|| {
+        while !should_stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((tcp_stream, address)) => {
+                    tcp_stream.set_nonblocking(false).ok();
+                    if sender.send((tcp_stream, address)).is_err() {
+                        break;
+                    }
+                },
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }).unwrap()
+}
+
+fn writer_thread(should_stop: Arc<AtomicBool>, connected: Arc<AtomicBool>, tcp_stream: TcpStream, outbound: Arc<Outbound>, codec: Arc<Mutex<Codec>>, capture: Option<Arc<Mutex<ProtocolCapture>>>, address: SocketAddr) -> JoinHandle<()> {
+    thread::Builder::new().name(String::from("TrafficViewerWriterThread")).spawn(move|| {
+        let mut writer = LineWriter::new(tcp_stream);
+        let mut consecutive_failures = 0;
+        // A token bucket holding up to a second's worth of packets
+        let rate = outbound.max_packets_per_second as f64;
+        let mut tokens = rate;
+        let mut last_refill = Instant::now();
+        let mut closing_since = None;
+
+        loop {
+            let closing = should_stop.load(Ordering::Relaxed) || !connected.load(Ordering::Relaxed);
+            if closing && closing_since.is_none() {
+                closing_since = Some(Instant::now());
+                writer.get_ref().set_write_timeout(Some(CLOSING_FLUSH_DEADLINE)).ok();
+            }
+            if closing_since.is_some_and(|since: Instant| since.elapsed() > CLOSING_FLUSH_DEADLINE) {
+                break;
+            }
+            let packet = {
+                let mut queue = outbound.queue.lock().unwrap();
+                loop {
+                    if let Some(packet) = queue.pop_front() {
+                        break Some(packet);
+                    }
+                    // Anything queued before the session closed is still written, so that error replies arrive
+                    if should_stop.load(Ordering::Relaxed) || !connected.load(Ordering::Relaxed) {
+                        break None;
+                    }
+                    queue = outbound.ready.wait_timeout(queue, Duration::from_millis(500)).unwrap().0;
+                }
+            };
+            let Some(packet) = packet else {
+                break;
+            };
+
+            // A closing session's last packets are written straight away
+            if rate > 0.0 && connected.load(Ordering::Relaxed) {
+                tokens = (tokens + last_refill.elapsed().as_secs_f64() * rate).min(rate);
+                last_refill = Instant::now();
+                if tokens < 1.0 {
+                    thread::sleep(Duration::from_secs_f64((1.0 - tokens) / rate));
+                    tokens = 1.0;
+                    last_refill = Instant::now();
+                }
+                tokens -= 1.0;
+            }
+
+            let bytes = codec.lock().unwrap().encode(&format!("{packet}\r\n"));
+            match writer.write_all(&bytes) {
+                Ok(_) => {
+                    consecutive_failures = 0;
+                    outbound.bytes_out.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                    outbound.packets_out.fetch_add(1, Ordering::Relaxed);
+                    if let Some(capture) = &capture {
+                        capture.lock().unwrap().record(Direction::Outbound, &packet);
+                    }
+                },
+                Err(e) => {
+                    consecutive_failures += 1;
+                    if consecutive_failures >= MAX_WRITE_FAILURES || !connected.load(Ordering::Relaxed) {
+                        println!("Closing connection from {} after {} failed writes: {:?}", address, consecutive_failures, e);
+                        break;
+                    }
+                },
+            }
+        }
+        connected.store(false, Ordering::Relaxed);
+        writer.get_ref().shutdown(std::net::Shutdown::Both).ok();
+    }).unwrap()
+}
+
+fn recv_thread(should_stop: Arc<AtomicBool>, connected: Arc<AtomicBool>, tcp_stream: TcpStream, sender: Sender<Vec<u8>>) -> JoinHandle<()> {
     thread::Builder::new().name(String::from("TrafficViewerRecvThread")).spawn(move|| {
         let mut reader = BufReader::new(tcp_stream);
-        
+
 
         while !should_stop.load(Ordering::Relaxed) {
             let mut buffer = Vec::with_capacity(512);
@@ -64,13 +804,10 @@ fn recv_thread(should_stop: Arc<AtomicBool>, tcp_stream: TcpStream, sender: Send
                     break;
                 },
                 Ok(_) => {
-                    let message = byte_slice_to_string(&buffer);
-                    println!("RECV: {}", message.trim());
-                    if let Ok(fsd_message) = fsd_interface::parse_message(message.trim()) {
-                        if let Err(e) = sender.send(fsd_message) {
-                            println!("{:?}", e);
-                            break;
-                        }
+                    // Decoded by the session, which knows the client's encoding
+                    if let Err(e) = sender.send(buffer) {
+                        println!("{:?}", e);
+                        break;
                     }
                 },
                 Err(e) => {
@@ -79,18 +816,212 @@ fn recv_thread(should_stop: Arc<AtomicBool>, tcp_stream: TcpStream, sender: Send
                 },
             }
         }
+        connected.store(false, Ordering::Relaxed);
         println!("RECV THREAD ENDING");
     }).unwrap()
 }
 
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Account;
 
-#[inline]
-fn byte_slice_to_string(slice: &[u8]) -> String {
-    slice.iter().map(|c| *c as char).collect()
-}
+    /// How long the server is given to deal with what a test client has sent
+    const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+    /// A client connected to a [`Server`] under test, which collects what the server sends it.
+    pub(crate) struct TestClient {
+        stream: TcpStream,
+        lines: Receiver<String>,
+    }
+    impl TestClient {
+        /// Connects to the server, and waits for it to accept the connection and send `$DI`.
+        pub(crate) fn connect(server: &mut Server) -> TestClient {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            let (tx, rx) = mpsc::channel();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            thread::spawn(move|| {
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 0) {
+                    if tx.send(line.trim().to_owned()).is_err() {
+                        break;
+                    }
+                    line.clear();
+                }
+            });
+            let mut client = TestClient { stream, lines: rx };
+            settle(server);
+            let handshake = client.received();
+            assert!(handshake.len() == 1 && handshake[0].starts_with("$DISERVER:CLIENT:"), "{:?}", handshake);
+            client
+        }
+
+        /// Connects and logs in with an `$ID` and an `#AA`, returning the client and the registration.
+        pub(crate) fn log_in(server: &mut Server, callsign: &str, cid: &str, password: &str, rating: u8) -> (TestClient, Vec<FsdMessageType>) {
+            let mut client = TestClient::connect(server);
+            let messages = client.send(server, &[
+                &format!("$ID{callsign}:SERVER:de1c:EuroScope 3.2:3:2:{cid}:abcdef12"),
+                &format!("#AA{callsign}:SERVER:Sam Controller:{cid}:{password}:{rating}:100"),
+            ]);
+            (client, messages)
+        }
+
+        /// Sends lines to the server, returning the messages it then passes on.
+        pub(crate) fn send(&mut self, server: &mut Server, lines: &[&str]) -> Vec<FsdMessageType> {
+            for line in lines {
+                self.stream.write_all(format!("{line}\r\n").as_bytes()).unwrap();
+            }
+            settle(server)
+        }
+
+        /// Gives the server time to send anything it has queued, then returns the lines received.
+        pub(crate) fn receive(&mut self, server: &mut Server) -> Vec<String> {
+            settle(server);
+            self.received()
+        }
+
+        /// The lines received since last asked, leaving out pings.
+        pub(crate) fn received(&mut self) -> Vec<String> {
+            self.lines.try_iter().filter(|line| !line.starts_with("$PISERVER")).collect()
+        }
+    }
+
+    /// Polls the server for a while, returning the messages it passes on.
+    pub(crate) fn settle(server: &mut Server) -> Vec<FsdMessageType> {
+        let start = Instant::now();
+        let mut messages = vec![];
+        while start.elapsed() < SETTLE_TIME {
+            messages.extend(server.poll());
+            thread::sleep(Duration::from_millis(5));
+        }
+        messages
+    }
+
+    pub(crate) fn start_server(config: ServerConfig) -> Server {
+        let config = ServerConfig { bind_address: String::from("127.0.0.1:0"), ..config };
+        Server::start(&config, &CaptureConfig::default(), Network::default()).unwrap()
+    }
+
+    #[test]
+    fn logs_clients_in() {
+        let mut server = start_server(ServerConfig::default());
+        let mut tower = TestClient::connect(&mut server);
+        assert_eq!(tower.send(&mut server, &["$IDEGLL_TWR:SERVER:de1c:EuroScope 3.2:3:2:1234567:abcdef12"]).len(), 0);
+        assert!(server.callsigns().is_empty());
+
+        let messages = tower.send(&mut server, &["#AAEGLL_TWR:SERVER:Sam Controller:1234567:password:3:100"]);
+        assert!(matches!(messages.as_slice(), [FsdMessageType::AtcRegisterMessage(msg)] if msg.from == "EGLL_TWR"), "{:?}", messages);
+        assert_eq!(server.callsigns(), ["EGLL_TWR"]);
+        assert_eq!(server.network_of("egll_twr"), Some(Network::Vatsim));
+        assert!(!server.is_observer("EGLL_TWR"));
+        assert!(tower.received().is_empty());
+
+        // Clients which log in later are introduced to it, and it to them
+        let (mut approach, _) = TestClient::log_in(&mut server, "EGLL_APP", "7654321", "password", 5);
+        assert_eq!(approach.received(), ["#AAEGLL_TWR:SERVER:Sam Controller:1234567:password:3:100"]);
+        assert_eq!(tower.received(), ["#AAEGLL_APP:SERVER:Sam Controller:7654321:password:5:100"]);
+        tower.send(&mut server, &["#TMEGLL_TWR:EGLL_APP:Hello"]);
+        assert_eq!(approach.received(), ["#TMEGLL_TWR:EGLL_APP:Hello"]);
+    }
+
+    #[test]
+    fn only_logged_in_clients_are_sessions() {
+        let mut server = start_server(ServerConfig::default());
+        let _probe = TestClient::connect(&mut server);
+        assert_eq!(server.stats().len(), 1);
+        assert!(!server.has_sessions());
+        let _tower = TestClient::log_in(&mut server, "EGLL_TWR", "1234567", "password", 3);
+        assert!(server.has_sessions());
+    }
+
+    #[test]
+    fn rejects_a_callsign_in_use() {
+        let mut server = start_server(ServerConfig::default());
+        let (mut first, _) = TestClient::log_in(&mut server, "EGLL_TWR", "1234567", "password", 3);
+        let (mut second, messages) = TestClient::log_in(&mut server, "egll_twr", "7654321", "password", 3);
+        assert!(messages.is_empty(), "{:?}", messages);
+        assert_eq!(second.received(), [FsdErrorMessage::new(SERVER_CALLSIGN, "egll_twr", FsdError::CallsignInUse).to_string()]);
+        assert_eq!(server.callsigns(), ["EGLL_TWR"]);
+        // The rejected client is disconnected, and nobody else hears about it
+        assert_eq!(server.stats().len(), 1);
+        assert!(first.received().is_empty());
+    }
+
+    #[test]
+    fn ignores_clients_which_havent_identified() {
+        let mut server = start_server(ServerConfig::default());
+        let (mut tower, _) = TestClient::log_in(&mut server, "EGLL_TWR", "1234567", "password", 3);
+        let mut client = TestClient::connect(&mut server);
+        assert!(client.send(&mut server, &["#TMEGLL_APP:EGLL_TWR:Hello", "%EGLL_APP:19400:4:100:5:51.47:-0.46:0"]).is_empty());
+        assert!(client.received().is_empty());
+        assert!(tower.received().is_empty());
+
+        // Only classic clients may register without identifying first
+        assert!(client.send(&mut server, &["#AAEGLL_APP:SERVER:Sam Controller:7654321:password:5:100"]).is_empty());
+        assert_eq!(client.received(), [FsdErrorMessage::new(SERVER_CALLSIGN, "EGLL_APP", FsdError::UnauthorisedClient).to_string()]);
+        assert_eq!(server.callsigns(), ["EGLL_TWR"]);
+    }
+
+    #[test]
+    fn observers_cant_change_anything() {
+        let config = ServerConfig {
+            accounts: vec![
+                Account { cid: String::from("1234567"), password: Some(String::from("secret")), tokens: vec![], observer: false, network: None },
+                Account { cid: String::from("7654321"), password: Some(String::from("secret")), tokens: vec![], observer: true, network: None },
+            ],
+            ..ServerConfig::default()
+        };
+        let mut server = start_server(config);
+        let (mut tower, _) = TestClient::log_in(&mut server, "EGLL_TWR", "1234567", "secret", 3);
+        let (mut observer, messages) = TestClient::log_in(&mut server, "EGLL_OBS", "7654321", "secret", 1);
+        assert_eq!(messages.len(), 1);
+        assert!(server.is_observer("EGLL_OBS"));
+        tower.received();
+        observer.received();
+
+        assert!(observer.send(&mut server, &["$CQEGLL_OBS:@94835:IT:BAW123", "#TMEGLL_OBS:EGLL_TWR:Hello"]).is_empty());
+        assert!(tower.received().is_empty());
+        // Asking for information is fine
+        let messages = observer.send(&mut server, &["$CQEGLL_OBS:EGLL_TWR:RN"]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(tower.received(), ["$CQEGLL_OBS:EGLL_TWR:RN"]);
+    }
 
-#[inline]
-fn string_to_byte_slice(string: &str) -> Vec<u8> {
-    string.chars().map(|c| c as u8).collect()
-}
\ No newline at end of file
+    fn queued(outbound: &Outbound) -> Vec<String> {
+        outbound.queue.lock().unwrap().packets.iter().cloned().collect()
+    }
+
+    #[test]
+    fn position_updates_replace_the_queued_one() {
+        let outbound = Outbound::new(10, 0);
+        outbound.push("@N:BAW1:1200:1:51.0:0.0:1000:0:0:0");
+        outbound.push("%EGLL_TWR:18700:4:50:3:51.0:0.0:0");
+        outbound.push("#TMSERVER:EGLL_TWR:Hello");
+        outbound.push("@N:BAW1:1200:1:51.1:0.0:1100:0:0:0");
+        outbound.push("%EGLL_TWR:18700:4:50:3:51.5:0.0:0");
+        assert_eq!(queued(&outbound), ["@N:BAW1:1200:1:51.1:0.0:1100:0:0:0", "%EGLL_TWR:18700:4:50:3:51.5:0.0:0", "#TMSERVER:EGLL_TWR:Hello"]);
+
+        // Once written, the next update queues behind the rest
+        let mut queue = outbound.queue.lock().unwrap();
+        assert_eq!(queue.pop_front().as_deref(), Some("@N:BAW1:1200:1:51.1:0.0:1100:0:0:0"));
+        drop(queue);
+        outbound.push("@N:BAW1:1200:1:51.2:0.0:1200:0:0:0");
+        outbound.push("%EGLL_TWR:18700:4:50:3:51.6:0.0:0");
+        assert_eq!(queued(&outbound), ["%EGLL_TWR:18700:4:50:3:51.6:0.0:0", "#TMSERVER:EGLL_TWR:Hello", "@N:BAW1:1200:1:51.2:0.0:1200:0:0:0"]);
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_oldest_packet() {
+        let outbound = Outbound::new(2, 0);
+        outbound.push("@N:BAW1:1200:1:51.0:0.0:1000:0:0:0");
+        outbound.push("@N:DLH2:1200:1:52.0:0.0:1000:0:0:0");
+        outbound.push("@N:AFR3:1200:1:53.0:0.0:1000:0:0:0");
+        assert_eq!(outbound.dropped.load(Ordering::Relaxed), 1);
+        // The dropped update's callsign queues afresh, and the others are still replaced in place
+        outbound.push("@N:BAW1:1200:1:51.1:0.0:1000:0:0:0");
+        outbound.push("@N:AFR3:1200:1:53.1:0.0:1000:0:0:0");
+        assert_eq!(queued(&outbound), ["@N:AFR3:1200:1:53.1:0.0:1000:0:0:0", "@N:BAW1:1200:1:51.1:0.0:1000:0:0:0"]);
+        assert_eq!(outbound.queue.lock().unwrap().positions.len(), 2);
+    }
+}
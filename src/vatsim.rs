@@ -1,5 +1,70 @@
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{flight_plan::NormalisedFlightPlan, network::{NetworkData, NetworkProvider}};
+
+const VATSIM_DATA_URL: &str = "https://data.vatsim.net/v3/vatsim-data.json";
+
+
+/// Reads VATSIM's v3 data feed.
+#[derive(Default)]
+pub struct VatsimProvider {
+    last_update: Option<String>,
+}
+
+impl NetworkProvider for VatsimProvider {
+    fn name(&self) -> &'static str {
+        "VATSIM"
+    }
+
+    fn data_url(&self) -> &'static str {
+        VATSIM_DATA_URL
+    }
+
+    fn parse(&mut self, body: &str) -> Result<Option<NetworkData>, String> {
+        let document = serde_json::from_str::<V3Document>(body).map_err(|e| e.to_string())?;
+        if self.last_update.as_deref() == Some(document.general.update_timestamp) {
+            return Ok(None);
+        }
+        self.last_update = Some(document.general.update_timestamp.to_owned());
+
+        Ok(Some(NetworkData {
+            pilots: parse_entries(&document.pilots),
+            prefiles: parse_entries::<Prefile>(&document.prefiles).into_iter().map(Details::from).collect(),
+            controllers: parse_entries(&document.controllers),
+            atis: parse_entries(&document.atis),
+            facilities: parse_entries(&document.facilities),
+        }))
+    }
+}
+
+/// The parts of the v3 document which are used. Each array entry is kept as a slice of the original
+/// text until it's parsed, so that a single malformed entry doesn't lose the rest.
+#[derive(Deserialize)]
+struct V3Document<'a> {
+    #[serde(borrow)]
+    general: V3General<'a>,
+    #[serde(borrow)]
+    pilots: Vec<&'a RawValue>,
+    #[serde(borrow, default)]
+    prefiles: Vec<&'a RawValue>,
+    #[serde(borrow, default)]
+    controllers: Vec<&'a RawValue>,
+    #[serde(borrow, default)]
+    atis: Vec<&'a RawValue>,
+    #[serde(borrow, default)]
+    facilities: Vec<&'a RawValue>,
+}
+
+#[derive(Deserialize)]
+struct V3General<'a> {
+    update_timestamp: &'a str,
+}
+
+fn parse_entries<'a, T: Deserialize<'a>>(entries: &[&'a RawValue]) -> Vec<T> {
+    entries.iter().filter_map(|entry| serde_json::from_str(entry.get()).ok()).collect()
+}
 
 
 
@@ -9,18 +74,89 @@ pub struct Details {
     pub name: String,
     pub callsign: String,
     pub transponder: String,
+    pub latitude: f64,
+    pub longitude: f64,
     pub altitude: i32,
+    pub groundspeed: u32,
     pub heading: u32,
     pub qnh_i_hg: f32,
     pub flight_plan: Option<FlightPlan>,
+    /// Whether these details come from a prefile rather than a connected pilot
+    #[serde(skip)]
+    pub prefiled: bool,
 }
 
+/// An entry in the feed's `prefiles` array: a flight plan filed by a pilot who hasn't connected yet.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Prefile {
+    pub cid: i32,
+    pub name: String,
+    pub callsign: String,
+    pub flight_plan: FlightPlan,
+}
+impl From<Prefile> for Details {
+    fn from(value: Prefile) -> Self {
+        Details {
+            cid: value.cid,
+            name: value.name,
+            callsign: value.callsign,
+            transponder: value.flight_plan.assigned_transponder.clone(),
+            latitude: 0.0,
+            longitude: 0.0,
+            altitude: 0,
+            groundspeed: 0,
+            heading: 0,
+            qnh_i_hg: 29.92,
+            flight_plan: Some(value.flight_plan),
+            prefiled: true,
+        }
+    }
+}
 
+
+/// An entry in the feed's `controllers` array.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Controller {
+    pub cid: i32,
+    pub name: String,
+    pub callsign: String,
+    pub frequency: String,
+    pub facility: u8,
+    pub rating: i8,
+    pub visual_range: u32,
+    pub text_atis: Option<Vec<String>>,
+}
+
+/// An entry in the feed's `atis` array.
 #[derive(Debug, Deserialize, Clone)]
+pub struct Atis {
+    pub callsign: String,
+    pub text_atis: Option<Vec<String>>,
+}
+
+/// An entry in the feed's `facilities` array, naming a [`Controller::facility`].
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Facility {
+    pub id: u8,
+    pub short: String,
+    pub long: String,
+}
+
+
+/// A flight plan as it appears in the feed. The time, level and speed fields are free text, so use
+/// [`FlightPlan::normalise`] to read them.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct FlightPlan {
     pub flight_rules: FlightRules,
-    #[serde(rename = "aircraft_faa")]
-    pub aircraft_type: String,
+    /// Aircraft type and equipment in ICAO form
+    #[serde(default)]
+    pub aircraft: String,
+    /// Aircraft type and equipment in FAA form
+    #[serde(default)]
+    pub aircraft_faa: String,
+    /// The ICAO type designator alone
+    #[serde(default)]
+    pub aircraft_short: String,
     #[serde(rename = "departure")]
     pub departure_icao: String,
     #[serde(rename = "arrival")]
@@ -28,77 +164,29 @@ pub struct FlightPlan {
     #[serde(rename = "alternate")]
     pub alternate_icao: String,
     pub cruise_tas: String,
-    altitude: String,
+    pub(crate) altitude: String,
     #[serde(rename = "deptime")]
-    departure_time: String,
-    enroute_time: String,
-    fuel_time: String,
+    pub(crate) departure_time: String,
+    pub(crate) enroute_time: String,
+    pub(crate) fuel_time: String,
     pub remarks: String,
     pub route: String,
     pub revision_id: usize,
     pub assigned_transponder: String,
 }
 impl FlightPlan {
-    pub fn altitude(&self) -> i32 {
-        match self.altitude.parse::<i32>() {
-            Ok(alt) => return alt,
-            Err(_) if self.altitude.starts_with("FL") && self.altitude.len() > 2 => {
-                match self.altitude[2..].parse::<i32>() {
-                    Ok(alt) => return alt * 100,
-                    Err(_) => return 0,
-                }
-            },
-            Err(_) => return 0,
-        }
-    }
-    pub fn departure_time(&self) -> (u8, u8) {
-        let hours = self.departure_time.get(0..2).and_then(|hours| hours.parse::<u8>().ok()).unwrap_or_default();
-        let mins = self.departure_time.get(2..2).and_then(|mins| mins.parse::<u8>().ok()).unwrap_or_default();
-        (hours, mins)
-    }
-    pub fn enroute_time(&self) -> (u8, u8) {
-        let hours = self.enroute_time.get(0..2).and_then(|hours| hours.parse::<u8>().ok()).unwrap_or_default();
-        let mins = self.enroute_time.get(2..2).and_then(|mins| mins.parse::<u8>().ok()).unwrap_or_default();
-        (hours, mins)
-    }
-    pub fn fuel_time(&self) -> (u8, u8) {
-        let hours = self.fuel_time.get(0..2).and_then(|hours| hours.parse::<u8>().ok()).unwrap_or_default();
-        let mins = self.fuel_time.get(2..2).and_then(|mins| mins.parse::<u8>().ok()).unwrap_or_default();
-        (hours, mins)
+    pub fn normalise(&self) -> NormalisedFlightPlan {
+        NormalisedFlightPlan::from(self)
     }
 }
 
 impl From<FlightPlan> for fsd_interface::FlightPlan {
     fn from(value: FlightPlan) -> Self {
-        let (hours_enroute, mins_enroute) = value.enroute_time();
-        let (hours_fuel, mins_fuel) = value.fuel_time();
-        let alt = value.altitude() as u32;
-        Self {
-            flight_rules: match value.flight_rules {
-                FlightRules::DVFR => fsd_interface::FlightRules::DVFR,
-                FlightRules::VFR => fsd_interface::FlightRules::VFR,
-                FlightRules::SVFR => fsd_interface::FlightRules::SVFR,
-                FlightRules::IFR => fsd_interface::FlightRules::IFR,
-            },
-            ac_type: value.aircraft_type,
-            filed_tas: value.cruise_tas.parse().unwrap_or(0),
-            origin: value.departure_icao,
-            etd: value.departure_time.parse().unwrap_or(0),
-            atd: value.departure_time.parse().unwrap_or(0),
-            cruise_level: alt,
-            destination: value.arrival_icao,
-            hours_enroute,
-            mins_enroute,
-            hours_fuel,
-            mins_fuel,
-            alternate: value.alternate_icao,
-            remarks: value.remarks,
-            route: value.route,
-        }
+        value.normalise().into()
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum FlightRules {
     #[serde(rename = "D")]
     DVFR,
@@ -108,4 +196,140 @@ pub enum FlightRules {
     SVFR,
     #[serde(rename = "I")]
     IFR,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, time::Instant};
+
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/vatsim-data.json");
+    /// A full-size document, with as many pilots, controllers and prefiles as VATSIM has at a busy time
+    const FULL_FIXTURE: &str = include_str!("../tests/fixtures/vatsim-data-full.json");
+
+    fn parse_fixture() -> NetworkData {
+        VatsimProvider::default().parse(FIXTURE).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parses_pilots_and_skips_malformed_entries() {
+        let data = parse_fixture();
+        let callsigns: Vec<&str> = data.pilots.iter().map(|pilot| pilot.callsign.as_str()).collect();
+        assert_eq!(callsigns, ["BAW256", "N172SP", "DLH4AB"]);
+
+        let pilot = &data.pilots[0];
+        assert_eq!(pilot.cid, 1234567);
+        assert_eq!(pilot.transponder, "2200");
+        assert_eq!(pilot.altitude, 83);
+        assert_eq!(pilot.heading, 272);
+        assert!((pilot.qnh_i_hg - 29.89).abs() < 0.001);
+        assert!(!pilot.prefiled);
+        assert!(data.pilots[2].flight_plan.is_none());
+    }
+
+    #[test]
+    fn parses_flight_plans() {
+        let data = parse_fixture();
+        let flight_plan = data.pilots[0].flight_plan.as_ref().unwrap();
+        assert_eq!(flight_plan.flight_rules, FlightRules::IFR);
+        assert_eq!(flight_plan.aircraft, "B772/H-SDE2E3FGHIJ2J3J4J5M1RWXY/LB1D1");
+        assert_eq!(flight_plan.aircraft_faa, "H/B772/L");
+        assert_eq!(flight_plan.aircraft_short, "B772");
+        assert_eq!((flight_plan.departure_icao.as_str(), flight_plan.arrival_icao.as_str(), flight_plan.alternate_icao.as_str()), ("EGLL", "KJFK", "KEWR"));
+        assert_eq!(flight_plan.departure_time, "1845");
+        assert_eq!(flight_plan.revision_id, 2);
+        assert_eq!(data.pilots[1].flight_plan.as_ref().unwrap().flight_rules, FlightRules::VFR);
+    }
+
+    #[test]
+    fn parses_prefiles_controllers_and_atis() {
+        let data = parse_fixture();
+        assert_eq!(data.prefiles.len(), 1);
+        let prefile = &data.prefiles[0];
+        assert_eq!(prefile.callsign, "EZY45KP");
+        assert!(prefile.prefiled);
+        assert_eq!(prefile.flight_plan.as_ref().unwrap().arrival_icao, "LFMN");
+
+        let controllers: Vec<&str> = data.controllers.iter().map(|controller| controller.callsign.as_str()).collect();
+        assert_eq!(controllers, ["EGLL_N_TWR", "LON_OBS"]);
+        assert_eq!(data.controllers[0].frequency, "118.700");
+        assert_eq!(data.controllers[0].facility, 4);
+        assert_eq!(data.controllers[0].text_atis.as_ref().unwrap().len(), 2);
+        assert_eq!(data.controllers[1].text_atis, None);
+
+        assert_eq!(data.atis.len(), 1);
+        assert_eq!(data.atis[0].callsign, "EGLL_ATIS");
+    }
+
+    #[test]
+    fn parses_facilities() {
+        let data = parse_fixture();
+        let facilities: Vec<(u8, &str)> = data.facilities.iter().map(|facility| (facility.id, facility.short.as_str())).collect();
+        assert_eq!(facilities, [(0, "OBS"), (1, "FSS"), (2, "DEL"), (3, "GND"), (4, "TWR"), (5, "APP"), (6, "CTR")]);
+        assert_eq!(data.facilities[5].long, "Approach/Departure");
+        // Older documents without the table still parse
+        let data = VatsimProvider::default().parse(&FIXTURE.replace("\"facilities\"", "\"unknown\"")).unwrap().unwrap();
+        assert!(data.facilities.is_empty());
+    }
+
+    #[test]
+    fn parses_a_full_size_document() {
+        let data = VatsimProvider::default().parse(FULL_FIXTURE).unwrap().unwrap();
+        assert_eq!(data.pilots.len(), 1500);
+        assert_eq!(data.pilots.iter().filter(|pilot| pilot.flight_plan.is_some()).count(), 1347);
+        assert_eq!(data.prefiles.len(), 300);
+        assert_eq!(data.controllers.len(), 209);
+        assert_eq!(data.atis.len(), 30);
+        assert_eq!(data.facilities.len(), 7);
+    }
+
+    #[test]
+    fn skips_unchanged_documents() {
+        let mut provider = VatsimProvider::default();
+        assert!(provider.parse(FIXTURE).unwrap().is_some());
+        assert!(provider.parse(FIXTURE).unwrap().is_none());
+        let updated = FIXTURE.replace("2024-06-12T18:30:15.7340937Z", "2024-06-12T18:30:30.1104529Z");
+        assert!(provider.parse(&updated).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_documents_which_are_not_the_feed() {
+        assert!(VatsimProvider::default().parse("{\"pilots\": []}").is_err());
+        assert!(VatsimProvider::default().parse("<html></html>").is_err());
+    }
+
+    /// Compares the typed parser with deserialising the whole document into a `serde_json::Value` and then
+    /// each pilot again from a clone of its `Value`, as the worker used to.
+    /// Run with `cargo test --release -- --ignored --nocapture benchmark`.
+    #[test]
+    #[ignore]
+    fn benchmark_full_size_document() {
+        const RUNS: u32 = 20;
+        let started = Instant::now();
+        for _ in 0..RUNS {
+            let document: serde_json::Value = serde_json::from_str(FULL_FIXTURE).unwrap();
+            let pilots: Vec<Details> = document["pilots"].as_array().unwrap().iter()
+                .filter_map(|pilot| serde_json::from_value(pilot.clone()).ok())
+                .collect();
+            black_box(pilots);
+        }
+        let untyped = started.elapsed() / RUNS;
+
+        let started = Instant::now();
+        for _ in 0..RUNS {
+            black_box(VatsimProvider::default().parse(FULL_FIXTURE).unwrap());
+        }
+        let typed = started.elapsed() / RUNS;
+
+        let started = Instant::now();
+        let mut provider = VatsimProvider::default();
+        provider.parse(FULL_FIXTURE).unwrap();
+        for _ in 0..RUNS {
+            black_box(provider.parse(FULL_FIXTURE).unwrap());
+        }
+        let unchanged = started.elapsed() / RUNS;
+
+        println!("{} bytes: Value {:?}, typed {:?}, unchanged {:?} per document", FULL_FIXTURE.len(), untyped, typed, unchanged);
+    }
+}
@@ -0,0 +1,328 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use serde::Deserialize;
+
+use crate::{feed::civil_time, fsuipc::{self, NewWeather, StationWeatherRequest}, worker::unix_timestamp};
+
+const SIM_METAR_CACHE_DURATION: Duration = Duration::from_secs(60);
+/// How long the simulator is given to look up a station before it's taken to be unknown
+const SIM_STATION_LOOKUP_TIMEOUT: Duration = Duration::from_millis(1500);
+const FEET_PER_M: f64 = 3.28084;
+const METRES_PER_STATUTE_MILE: f64 = 1609.344;
+
+
+
+/// Where replies to `MetarRequestMessage`s come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetarSource {
+    /// The METARs downloaded from VATSIM
+    #[default]
+    Vatsim,
+    /// METARs generated from the simulator's weather. Stations unknown to the simulator get the weather at the user's aircraft.
+    Simulator,
+    /// METARs generated from the simulator's weather where the simulator knows the station, otherwise the VATSIM ones
+    PreferSimulator,
+}
+
+
+/// Surface weather at a single point, in the units a METAR uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimWeather {
+    pub wind_direction: u16,
+    pub wind_speed: u16,
+    pub wind_gust: Option<u16>,
+    pub visibility_m: u32,
+    pub clouds: Vec<CloudLayer>,
+    pub precipitation: Option<Precipitation>,
+    pub temperature: i16,
+    pub dew_point: i16,
+    pub qnh_mb: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloudLayer {
+    pub coverage_octas: u8,
+    /// Feet above the station
+    pub base_ft: u32,
+    pub cumulonimbus: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Precipitation {
+    pub snow: bool,
+    /// 0 (very light) to 5 (very heavy)
+    pub rate: u8,
+}
+
+impl From<&NewWeather> for SimWeather {
+    fn from(weather: &NewWeather) -> Self {
+        let elevation = weather.elevation as i32;
+        let wind_count = (weather.winds_count.max(0) as usize).min(weather.wind.len());
+        let temp_count = (weather.temp_count.max(0) as usize).min(weather.temp.len());
+        let cloud_count = (weather.clouds_count.max(0) as usize).min(weather.cloud.len());
+
+        // Index 0 is always the surface layer
+        let surface_wind = weather.wind[..wind_count].first().copied();
+        let surface_temp = weather.temp[..temp_count].first().copied();
+
+        let mut clouds: Vec<CloudLayer> = weather.cloud[..cloud_count].iter()
+            .filter(|cloud| cloud.coverage > 0)
+            .map(|cloud| CloudLayer {
+                coverage_octas: cloud.coverage.min(8),
+                base_ft: ((cloud.lower_alt as i32 - elevation).max(0) as f64 * FEET_PER_M) as u32,
+                cumulonimbus: cloud.cloud_type == 10,
+            })
+            .collect();
+        clouds.sort_by_key(|cloud| cloud.base_ft);
+
+        let precipitation = weather.cloud[..cloud_count].iter()
+            .filter(|cloud| cloud.precip_type != 0)
+            .map(|cloud| Precipitation { snow: cloud.precip_type == 2, rate: cloud.precip_rate })
+            .max_by_key(|precipitation| precipitation.rate);
+
+        let (wind_direction, wind_speed, wind_gust) = match surface_wind {
+            Some(wind) => {
+                let (direction, speed, gust) = (wind.direction, wind.speed, wind.gust);
+                let direction = ((direction as f64 * 360.0 / 65536.0).round() as u16) % 360;
+                (if direction == 0 { 360 } else { direction }, speed, Some(gust).filter(|gust| *gust > speed))
+            },
+            None => (0, 0, None),
+        };
+        let (temperature, dew_point) = match surface_temp {
+            Some(temp) => (temp.day, temp.dew_point),
+            None => (15, 5),
+        };
+        let vis = weather.vis;
+        let pressure = weather.pressure;
+
+        SimWeather {
+            wind_direction,
+            wind_speed,
+            wind_gust,
+            visibility_m: (vis.range as f64 / 100.0 * METRES_PER_STATUTE_MILE) as u32,
+            clouds,
+            precipitation,
+            temperature,
+            dew_point,
+            qnh_mb: (pressure.pressure as f64 / 16.0).round() as u16,
+        }
+    }
+}
+
+impl SimWeather {
+    /// Encodes the weather as a METAR for `icao`, observed at the given Unix time.
+    pub fn to_metar(&self, icao: &str, observed_at: u64) -> String {
+        let (_, _, day, hours, minutes, _) = civil_time(observed_at);
+        let mut groups = vec![icao.to_uppercase(), format!("{:02}{:02}{:02}Z", day, hours, minutes)];
+
+        groups.push(match (self.wind_speed, self.wind_gust) {
+            (0, _) => String::from("00000KT"),
+            (speed, Some(gust)) => format!("{:03}{:02}G{:02}KT", self.wind_direction, speed, gust),
+            (speed, None) => format!("{:03}{:02}KT", self.wind_direction, speed),
+        });
+
+        let cavok = self.visibility_m >= 10000
+            && self.precipitation.is_none()
+            && self.clouds.iter().all(|cloud| cloud.base_ft >= 5000 && !cloud.cumulonimbus);
+        if cavok {
+            groups.push(String::from("CAVOK"));
+        } else {
+            groups.push(format!("{:04}", encode_visibility(self.visibility_m)));
+            if let Some(precipitation) = self.precipitation {
+                let intensity = match precipitation.rate {
+                    0 | 1 => "-",
+                    2 | 3 => "",
+                    _ => "+",
+                };
+                groups.push(format!("{}{}", intensity, if precipitation.snow { "SN" } else { "RA" }));
+            } else if self.visibility_m < 5000 {
+                groups.push(String::from(if self.visibility_m < 1000 { "FG" } else { "BR" }));
+            }
+            if self.clouds.is_empty() {
+                groups.push(String::from("NSC"));
+            }
+            for cloud in &self.clouds {
+                let coverage = match cloud.coverage_octas {
+                    1 | 2 => "FEW",
+                    3 | 4 => "SCT",
+                    5..=7 => "BKN",
+                    _ => "OVC",
+                };
+                groups.push(format!("{}{:03}{}", coverage, (cloud.base_ft / 100).min(999), if cloud.cumulonimbus { "CB" } else { "" }));
+            }
+        }
+
+        groups.push(format!("{}/{}", encode_temperature(self.temperature), encode_temperature(self.dew_point.min(self.temperature))));
+        groups.push(format!("Q{:04}", self.qnh_mb));
+        groups.join(" ")
+    }
+}
+
+/// Rounds a visibility to the steps used in METARs.
+fn encode_visibility(visibility_m: u32) -> u32 {
+    match visibility_m {
+        10000.. => 9999,
+        5000.. => visibility_m / 1000 * 1000,
+        800.. => visibility_m / 100 * 100,
+        _ => visibility_m / 50 * 50,
+    }
+}
+
+fn encode_temperature(temperature: i16) -> String {
+    if temperature < 0 {
+        format!("M{:02}", -temperature)
+    } else {
+        format!("{:02}", temperature)
+    }
+}
+
+
+/// The simulator's METAR for a station, as far as it's known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StationMetar {
+    Available(String),
+    /// The simulator doesn't know the station
+    Unknown,
+    /// The simulator is still looking the station up, so try again on a later tick
+    Pending,
+}
+
+
+/// Generates METARs from the simulator's weather, caching each station for a short while so that
+/// repeated requests don't each wait on the simulator.
+///
+/// The simulator looks up one station at a time and answers asynchronously, so lookups never wait for it:
+/// a station which isn't cached is requested, and its METAR is available on a later call once the
+/// simulator has answered. Until then a stale METAR is returned if there is one.
+#[derive(Default)]
+pub struct SimWeatherReader {
+    cache: HashMap<String, (Instant, Option<String>)>,
+    pending: Option<(String, Instant, StationWeatherRequest)>,
+}
+
+impl SimWeatherReader {
+    /// Returns a METAR for `icao` generated from the simulator's weather at that station.
+    pub fn station_metar(&mut self, icao: &str) -> StationMetar {
+        let icao = icao.to_uppercase();
+        self.collect_pending();
+        let cached = self.cache.get(&icao).cloned();
+        if cached.as_ref().is_none_or(|(fetched, _)| fetched.elapsed() >= SIM_METAR_CACHE_DURATION) && self.pending.is_none() {
+            match fsuipc::request_weather_at_station(&icao) {
+                Ok(request) => self.pending = Some((icao.clone(), Instant::now(), request)),
+                Err(error) => {
+                    println!("Unable to read simulator weather for {}: {}", icao, error);
+                    self.cache.insert(icao.clone(), (Instant::now(), None));
+                    return StationMetar::Unknown;
+                },
+            }
+        }
+        match cached {
+            Some((_, Some(metar))) => StationMetar::Available(metar),
+            Some((_, None)) => StationMetar::Unknown,
+            None => StationMetar::Pending,
+        }
+    }
+
+    /// Caches the answer to the outstanding station request if the simulator has given it, or gives up on
+    /// the station if it's taking too long.
+    fn collect_pending(&mut self) {
+        let Some((icao, requested, request)) = self.pending.as_ref() else {
+            return;
+        };
+        let metar = match fsuipc::read_requested_weather(request) {
+            Ok(Some(weather)) => Some(SimWeather::from(&weather).to_metar(icao, unix_timestamp())),
+            Ok(None) if requested.elapsed() < SIM_STATION_LOOKUP_TIMEOUT => return,
+            Ok(None) => None,
+            Err(error) => {
+                println!("Unable to read simulator weather for {}: {}", icao, error);
+                None
+            },
+        };
+        self.cache.insert(icao.clone(), (Instant::now(), metar));
+        self.pending = None;
+    }
+    /// Returns a METAR generated from the simulator's weather at the user's aircraft, reported as though observed at `icao`.
+    pub fn aircraft_metar(&self, icao: &str) -> Option<String> {
+        match fsuipc::get_weather_at_aircraft() {
+            Ok(weather) => Some(SimWeather::from(&weather).to_metar(icao, unix_timestamp())),
+            Err(error) => {
+                println!("Unable to read simulator weather at aircraft: {}", error);
+                None
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2026-10-18 10:20:00 UTC
+    const OBSERVED_AT: u64 = 1792318800;
+
+    fn weather() -> SimWeather {
+        SimWeather {
+            wind_direction: 250,
+            wind_speed: 12,
+            wind_gust: None,
+            visibility_m: 20000,
+            clouds: vec![],
+            precipitation: None,
+            temperature: 15,
+            dew_point: 5,
+            qnh_mb: 1013,
+        }
+    }
+
+    fn cloud(coverage_octas: u8, base_ft: u32, cumulonimbus: bool) -> CloudLayer {
+        CloudLayer { coverage_octas, base_ft, cumulonimbus }
+    }
+
+    #[test]
+    fn encodes_calm_and_gusting_winds() {
+        let calm = SimWeather { wind_direction: 360, wind_speed: 0, wind_gust: Some(5), ..weather() };
+        assert_eq!(calm.to_metar("egll", OBSERVED_AT), "EGLL 181020Z 00000KT CAVOK 15/05 Q1013");
+        assert_eq!(weather().to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 25012KT CAVOK 15/05 Q1013");
+        let gusting = SimWeather { wind_direction: 90, wind_speed: 15, wind_gust: Some(28), ..weather() };
+        assert_eq!(gusting.to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 09015G28KT CAVOK 15/05 Q1013");
+    }
+
+    #[test]
+    fn encodes_cavok_only_without_significant_weather() {
+        let high_cloud = SimWeather { clouds: vec![cloud(2, 6000, false), cloud(8, 12000, false)], ..weather() };
+        assert_eq!(high_cloud.to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 25012KT CAVOK 15/05 Q1013");
+        let cumulonimbus = SimWeather { clouds: vec![cloud(2, 6000, true)], ..weather() };
+        assert_eq!(cumulonimbus.to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 25012KT 9999 FEW060CB 15/05 Q1013");
+        let low_cloud = SimWeather { visibility_m: 8400, clouds: vec![cloud(4, 2500, false), cloud(6, 4000, false)], ..weather() };
+        assert_eq!(low_cloud.to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 25012KT 8000 SCT025 BKN040 15/05 Q1013");
+        let rain = SimWeather { precipitation: Some(Precipitation { snow: false, rate: 1 }), ..weather() };
+        assert_eq!(rain.to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 25012KT 9999 -RA NSC 15/05 Q1013");
+        let fog = SimWeather { visibility_m: 640, ..weather() };
+        assert_eq!(fog.to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 25012KT 0600 FG NSC 15/05 Q1013");
+    }
+
+    #[test]
+    fn encodes_negative_temperatures() {
+        let snow = SimWeather {
+            visibility_m: 1530,
+            precipitation: Some(Precipitation { snow: true, rate: 4 }),
+            clouds: vec![cloud(8, 800, false)],
+            temperature: -3,
+            dew_point: -7,
+            qnh_mb: 998,
+            ..weather()
+        };
+        assert_eq!(snow.to_metar("ENGM", OBSERVED_AT), "ENGM 181020Z 25012KT 1500 +SN OVC008 M03/M07 Q0998");
+        // The dew point can't be above the temperature
+        let mist = SimWeather { visibility_m: 3000, temperature: -1, dew_point: 2, ..weather() };
+        assert_eq!(mist.to_metar("ENGM", OBSERVED_AT), "ENGM 181020Z 25012KT 3000 BR NSC M01/M01 Q1013");
+    }
+
+    #[test]
+    fn clamps_cloud_bases() {
+        let overcast = SimWeather { visibility_m: 6000, clouds: vec![cloud(8, 150000, false)], ..weather() };
+        assert_eq!(overcast.to_metar("EGLL", OBSERVED_AT), "EGLL 181020Z 25012KT 6000 OVC999 15/05 Q1013");
+    }
+}
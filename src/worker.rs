@@ -1,80 +1,177 @@
-use std::{collections::HashMap, sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use std::{collections::{HashMap, HashSet}, io::Read, sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use serde_json::Value;
+use arc_swap::ArcSwap;
+use serde::Serialize;
 
-use crate::vatsim::Details;
+use crate::{config::{MetarConfig, NetworkConfig}, network::{NetworkData, NetworkProvider}, vatsim::{Atis, Controller, Details, Facility, FlightPlan}};
 
-const VATSIM_DATA_URL: &str = "https://data.vatsim.net/v3/vatsim-data.json";
-const VATSIM_METARS_URL: &str = "https://metar.vatsim.net/metar.php?id=all";
+const VATSIM_METARS_URL: &str = "https://metar.vatsim.net/metar.php";
 
 const METAR_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 10);
-const VATSIM_DATA_REFRESH_INTERVAL: Duration = Duration::from_secs(20);
+const NETWORK_DATA_REFRESH_INTERVAL: Duration = Duration::from_secs(20);
+/// The largest document which will be downloaded. The full VATSIM feed is several megabytes at peak times.
+const MAX_DOCUMENT_SIZE: u64 = 64 * 1024 * 1024;
 
 
 
 
 
+/// Downloads METARs and network data on a background thread.
+///
+/// Each refresh builds a complete new snapshot which is then swapped in atomically, so readers never
+/// wait on the worker and always see one refresh's data in its entirety.
 pub struct Worker {
-    vatsim_data_last_refreshed: Instant,
-    metars_last_refreshed: Instant,
+    network_data_last_refreshed: Option<Instant>,
+    metars_last_refreshed: Option<Instant>,
+    fetches_metars: bool,
+    idle: bool,
     sender: Sender<WorkerCommand>,
     thread: Option<JoinHandle<()>>,
-    metars: Arc<Mutex<HashMap<String, String>>>,
-    vatsim_data: Arc<Mutex<HashMap<String, (Details, bool)>>>,
+    metars: Arc<ArcSwap<HashMap<String, String>>>,
+    metar_overrides: Arc<HashMap<String, String>>,
+    /// Stations requested on their own since the last full METAR refresh, which aren't worth asking for again
+    requested_stations: Arc<Mutex<HashSet<String>>>,
+    network: Arc<ArcSwap<NetworkSnapshot>>,
+    status: Arc<Mutex<WorkerStatus>>,
+    subscribers: Arc<Mutex<Vec<Sender<NetworkEvent>>>>,
 }
 
 impl Worker {
 
-    pub fn start() -> Worker {
+    /// Starts the worker thread, which downloads METARs and pilot and controller data. Any METAR overrides
+    /// take precedence over the METARs downloaded from VATSIM. Without a METAR config, the worker only
+    /// downloads network data, e.g. for a second network when the METARs already come from another worker.
+    ///
+    /// The worker starts out idle, and doesn't download anything until [`Worker::set_idle`] is called.
+    pub fn start(metar_config: Option<&MetarConfig>, network_config: &NetworkConfig) -> Worker {
         let (tx, rx) = mpsc::channel();
-        let metars = Arc::new(Mutex::new(HashMap::new()));
-        let vatsim_data = Arc::new(Mutex::new(HashMap::new()));
+        let metars = Arc::new(ArcSwap::from_pointee(HashMap::new()));
+        let network = Arc::new(ArcSwap::from_pointee(NetworkSnapshot::default()));
+        let status = Arc::new(Mutex::new(WorkerStatus::default()));
+        let subscribers = Arc::new(Mutex::new(vec![]));
+        let requested_stations = Arc::new(Mutex::new(HashSet::new()));
+        let sources = Sources {
+            metar_url: metar_config.map(|config| config.url.clone().unwrap_or_else(|| String::from(VATSIM_METARS_URL))),
+            network_data_url: network_config.data_url.clone(),
+            provider: network_config.provider.provider(),
+        };
 
-        let mut worker = Worker {
-            vatsim_data_last_refreshed: Instant::now(),
-            metars_last_refreshed: Instant::now(),
+        Worker {
+            network_data_last_refreshed: None,
+            metars_last_refreshed: None,
+            fetches_metars: metar_config.is_some(),
+            idle: true,
             sender: tx,
-            thread: Some(worker_thread(rx, Arc::clone(&metars), Arc::clone(&vatsim_data))),
+            thread: Some(worker_thread(rx, sources, Arc::clone(&metars), Arc::clone(&requested_stations), Arc::clone(&network), Arc::clone(&status), Arc::clone(&subscribers))),
             metars,
-            vatsim_data,
-        };
-        worker.refresh_metars();
-        worker.refresh_vatsim_data();
-        worker
+            metar_overrides: Arc::new(metar_config.iter().flat_map(|config| config.overrides.iter()).map(|(icao, metar)| (icao.to_uppercase(), metar.clone())).collect()),
+            requested_stations,
+            network,
+            status,
+            subscribers,
+        }
     }
     pub fn refresh_metars(&mut self) {
-        self.metars_last_refreshed = Instant::now();
+        self.metars_last_refreshed = Some(Instant::now());
         self.sender.send(WorkerCommand::RefreshMetars).ok();
     }
 
-    pub fn refresh_vatsim_data(&mut self) {
-        self.vatsim_data_last_refreshed = Instant::now();
-        self.sender.send(WorkerCommand::RefreshVatsimData).ok();
+    pub fn refresh_network_data(&mut self) {
+        self.network_data_last_refreshed = Some(Instant::now());
+        self.sender.send(WorkerCommand::RefreshNetworkData).ok();
+    }
+
+    /// Pauses or resumes the periodic refreshes. Anything which has become stale while idle is refreshed
+    /// as soon as the worker resumes.
+    pub fn set_idle(&mut self, idle: bool) {
+        if idle != self.idle {
+            println!("Worker {}", if idle { "idle" } else { "resuming" });
+        }
+        self.idle = idle;
+        self.tick();
     }
 
     pub fn tick(&mut self) {
-        if self.metars_last_refreshed.elapsed() > METAR_REFRESH_INTERVAL {
+        if self.idle {
+            return;
+        }
+        if self.fetches_metars && self.metars_last_refreshed.is_none_or(|refreshed| refreshed.elapsed() > METAR_REFRESH_INTERVAL) {
             self.refresh_metars();
         }
-        if self.vatsim_data_last_refreshed.elapsed() > VATSIM_DATA_REFRESH_INTERVAL {
-            self.refresh_vatsim_data();
+        if self.network_data_last_refreshed.is_none_or(|refreshed| refreshed.elapsed() > NETWORK_DATA_REFRESH_INTERVAL) {
+            self.refresh_network_data();
         }
     }
 
     pub fn get_metar(&self, icao: &str) -> Option<String> {
         let icao = icao.to_uppercase();
-        self.metars.lock().unwrap().get(&icao).map(|value| value.clone())
+        if let Some(metar) = self.metar_overrides.get(&icao) {
+            return Some(metar.clone());
+        }
+        let metar = self.metars.load().get(&icao).cloned();
+        // Not in the full table, which may be stale; it can be fetched on its own for next time, but only
+        // once until the table is refreshed, however often it's asked for meanwhile
+        if metar.is_none() && self.fetches_metars && self.requested_stations.lock().unwrap().insert(icao.clone()) {
+            self.sender.send(WorkerCommand::FetchMetar(icao)).ok();
+        }
+        metar
+    }
+
+    pub fn get_aircraft_details(&self, callsign: &str) -> Option<Details> {
+        self.network.load().pilots.get(&callsign.to_uppercase()).cloned()
     }
 
-    pub fn get_aircraft_details(&mut self, callsign: &str) -> Option<(Details, bool)> {
-        let callsign = callsign.to_uppercase();
-        let mut data = self.vatsim_data.lock().unwrap();
-        let mut ret_val = None;
-        if let Some(value) = data.get_mut(&callsign) {
-            ret_val = Some(value.clone());
-            value.1 = false;
+    /// Returns a receiver for [`NetworkEvent`]s. Each subscriber gets its own copy of every event, starting
+    /// with a [`NetworkEvent::PilotAdded`] for each pilot already known.
+    pub fn subscribe(&self) -> Receiver<NetworkEvent> {
+        let (tx, rx) = mpsc::channel();
+        // The worker holds this lock while it publishes, so the snapshot and the events can't get out of step
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for callsign in self.network.load().pilots.keys() {
+            tx.send(NetworkEvent::PilotAdded(callsign.clone())).ok();
+        }
+        subscribers.push(tx);
+        rx
+    }
+
+    /// Returns whether there are details for a callsign.
+    pub fn has_details(&self, callsign: &str) -> bool {
+        self.network.load().pilots.contains_key(&callsign.to_uppercase())
+    }
+
+    pub fn get_flight_plan(&self, callsign: &str) -> Option<FlightPlan> {
+        self.network.load().pilots.get(&callsign.to_uppercase()).and_then(|details| details.flight_plan.clone())
+    }
+
+    /// Returns the callsigns of every aircraft with a flight plan, including prefiles.
+    pub fn flight_plan_callsigns(&self) -> HashSet<String> {
+        self.network.load().flight_plan_callsigns()
+    }
+
+    /// Returns the pilots connected to the network as of the last refresh.
+    pub fn get_online_pilots(&self) -> Vec<Details> {
+        let network = self.network.load();
+        network.online.iter().filter_map(|callsign| network.pilots.get(callsign)).cloned().collect()
+    }
+
+    /// Returns the controllers currently online on the network.
+    pub fn get_controllers(&self) -> Vec<Controller> {
+        self.network.load().controllers.clone()
+    }
+
+    /// Returns the ATIS currently published on the network under `callsign`, e.g. `EGLL_ATIS`.
+    pub fn get_atis(&self, callsign: &str) -> Option<Atis> {
+        self.network.load().atis.get(&callsign.to_uppercase()).cloned()
+    }
+
+    /// Returns a handle which other threads can use to read the worker's data.
+    pub fn handle(&self) -> WorkerHandle {
+        WorkerHandle {
+            metars: Arc::clone(&self.metars),
+            metar_overrides: Arc::clone(&self.metar_overrides),
+            network: Arc::clone(&self.network),
+            status: Arc::clone(&self.status),
         }
-        return ret_val;
     }
 }
 impl Drop for Worker {
@@ -85,78 +182,291 @@ impl Drop for Worker {
         }
     }
 }
+
+/// Read-only access to the worker's data from other threads.
+#[derive(Clone)]
+pub struct WorkerHandle {
+    metars: Arc<ArcSwap<HashMap<String, String>>>,
+    metar_overrides: Arc<HashMap<String, String>>,
+    network: Arc<ArcSwap<NetworkSnapshot>>,
+    status: Arc<Mutex<WorkerStatus>>,
+}
+impl WorkerHandle {
+    /// Returns the full METAR table, with any local overrides applied.
+    pub fn metars(&self) -> HashMap<String, String> {
+        let mut metars = HashMap::clone(&self.metars.load());
+        metars.extend(self.metar_overrides.iter().map(|(icao, metar)| (icao.clone(), metar.clone())));
+        metars
+    }
+
+    /// Returns the METARs for every station whose ICAO code starts with `prefix`, sorted by ICAO code.
+    /// An empty prefix returns every METAR.
+    pub fn metars_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.to_uppercase();
+        let metars = self.metars.load();
+        let mut matching: Vec<(&String, &String)> = metars.iter()
+            .filter(|(icao, _)| !self.metar_overrides.contains_key(*icao))
+            .chain(self.metar_overrides.iter())
+            .filter(|(icao, _)| icao.starts_with(&prefix))
+            .collect();
+        matching.sort_by(|a, b| a.0.cmp(b.0));
+        matching.into_iter().map(|(_, metar)| metar.clone()).collect()
+    }
+
+    pub fn details(&self, callsign: &str) -> Option<Details> {
+        self.network.load().pilots.get(&callsign.to_uppercase()).cloned()
+    }
+
+    /// Returns the controller facility types published by the network, which may be empty.
+    pub fn facilities(&self) -> Vec<Facility> {
+        self.network.load().facilities.clone()
+    }
+
+    pub fn status(&self) -> WorkerStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Everything known from the network as of one refresh.
+#[derive(Debug, Default)]
+pub struct NetworkSnapshot {
+    /// Connected pilots, prefiles, and disconnected pilots who still have a prefile, keyed by callsign
+    pub pilots: HashMap<String, Details>,
+    /// Callsigns of the pilots who were connected
+    pub online: HashSet<String>,
+    pub controllers: Vec<Controller>,
+    pub atis: HashMap<String, Atis>,
+    pub facilities: Vec<Facility>,
+}
+
+impl NetworkSnapshot {
+    /// Builds the snapshot which follows `previous` given freshly downloaded `data`, along with the
+    /// events describing the difference between the two.
+    pub fn next(previous: &NetworkSnapshot, data: NetworkData) -> (NetworkSnapshot, Vec<NetworkEvent>) {
+        let mut events = vec![];
+        let mut pilots = HashMap::with_capacity(data.pilots.len() + data.prefiles.len());
+        let mut online = HashSet::with_capacity(data.pilots.len());
+
+        for new in data.pilots.into_iter() {
+            match previous.pilots.get(&new.callsign) {
+                None => events.push(NetworkEvent::PilotAdded(new.callsign.clone())),
+                Some(existing) => {
+                    if existing.flight_plan != new.flight_plan {
+                        events.push(NetworkEvent::FlightPlanChanged(new.callsign.clone()));
+                    }
+                    if existing.transponder != new.transponder {
+                        events.push(NetworkEvent::SquawkChanged(new.callsign.clone()));
+                    }
+                },
+            }
+            online.insert(new.callsign.clone());
+            pilots.insert(new.callsign.clone(), new);
+        }
+
+        // Prefiles are only used for callsigns which have no live pilot
+        for new in data.prefiles.into_iter() {
+            if online.contains(&new.callsign) {
+                continue;
+            }
+            match previous.pilots.get(&new.callsign) {
+                // A pilot who has disconnected keeps their live details while they have a prefile
+                Some(existing) if !existing.prefiled => {
+                    pilots.insert(new.callsign.clone(), existing.clone());
+                    continue;
+                },
+                Some(existing) if existing.flight_plan != new.flight_plan => events.push(NetworkEvent::FlightPlanChanged(new.callsign.clone())),
+                Some(_) => {},
+                None => events.push(NetworkEvent::PilotAdded(new.callsign.clone())),
+            }
+            pilots.insert(new.callsign.clone(), new);
+        }
+
+        for callsign in previous.pilots.keys() {
+            if !pilots.contains_key(callsign) {
+                events.push(NetworkEvent::PilotRemoved(callsign.clone()));
+            }
+        }
+
+        let snapshot = NetworkSnapshot {
+            pilots,
+            online,
+            controllers: data.controllers,
+            atis: data.atis.into_iter().map(|atis| (atis.callsign.clone(), atis)).collect(),
+            facilities: data.facilities,
+        };
+        (snapshot, events)
+    }
+
+    /// The callsigns of every aircraft with a flight plan, including prefiles and disconnected pilots who
+    /// still have one.
+    pub fn flight_plan_callsigns(&self) -> HashSet<String> {
+        self.pilots.iter().filter(|(_, details)| details.flight_plan.is_some()).map(|(callsign, _)| callsign.clone()).collect()
+    }
+}
+
+/// Health information about the worker's most recent refreshes. Timestamps are seconds since the Unix epoch.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WorkerStatus {
+    pub metars_last_updated: Option<u64>,
+    pub metar_count: usize,
+    pub metars_last_error: Option<String>,
+    /// When the feed was last downloaded successfully, whether or not it had changed
+    pub network_data_last_updated: Option<u64>,
+    pub pilot_count: usize,
+    pub network_data_last_error: Option<String>,
+}
+
+/// A change to the network data, as found by a refresh. Events carry the pilot's callsign; the new
+/// details can be read from the worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkEvent {
+    PilotAdded(String),
+    PilotRemoved(String),
+    FlightPlanChanged(String),
+    SquawkChanged(String),
+}
+
 enum WorkerCommand {
-    RefreshVatsimData,
+    RefreshNetworkData,
     RefreshMetars,
+    FetchMetar(String),
     Stop,
 
 }
 
-fn worker_thread(receiver: Receiver<WorkerCommand>, metars: Arc<Mutex<HashMap<String, String>>>, vatsim_data: Arc<Mutex<HashMap<String, (Details, bool)>>>,) -> JoinHandle<()> {
+/// Where the worker downloads from.
+struct Sources {
+    metar_url: Option<String>,
+    network_data_url: Option<String>,
+    provider: Box<dyn NetworkProvider>,
+}
+
+/// The validators from the last response for a URL, sent back so that the server can answer
+/// `304 Not Modified` if nothing has changed.
+#[derive(Default)]
+struct CacheValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Downloads `url`, returning `None` if it hasn't changed since `validators` were recorded.
+fn fetch_if_modified(url: &str, validators: &mut CacheValidators) -> Result<Option<String>, String> {
+    let mut request = ureq::get(url);
+    if let Some(etag) = &validators.etag {
+        request = request.set("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.set("If-Modified-Since", last_modified);
+    }
+    let response = request.call().map_err(|e| e.to_string())?;
+    if response.status() == 304 {
+        return Ok(None);
+    }
+    validators.etag = response.header("ETag").map(str::to_owned);
+    validators.last_modified = response.header("Last-Modified").map(str::to_owned);
+    // `into_string` gives up at 10 MB, which the VATSIM feed can exceed
+    let mut body = String::new();
+    response.into_reader().take(MAX_DOCUMENT_SIZE + 1).read_to_string(&mut body).map_err(|e| e.to_string())?;
+    if body.len() as u64 > MAX_DOCUMENT_SIZE {
+        return Err(format!("{} is larger than {} bytes", url, MAX_DOCUMENT_SIZE));
+    }
+    Ok(Some(body))
+}
+
+fn worker_thread(receiver: Receiver<WorkerCommand>, mut sources: Sources, metars: Arc<ArcSwap<HashMap<String, String>>>, requested_stations: Arc<Mutex<HashSet<String>>>, network: Arc<ArcSwap<NetworkSnapshot>>, status: Arc<Mutex<WorkerStatus>>, subscribers: Arc<Mutex<Vec<Sender<NetworkEvent>>>>) -> JoinHandle<()> {
     thread::Builder::new().name(String::from("TrafficViewerWorker")).spawn(move|| {
+        let mut metar_validators = CacheValidators::default();
+        let mut network_data_validators = CacheValidators::default();
         loop {
             match receiver.recv() {
                 Ok(command) => match command {
                     WorkerCommand::RefreshMetars => {
+                        let Some(metar_url) = &sources.metar_url else {
+                            continue;
+                        };
                         let mut count = 0;
-                        match ureq::get(VATSIM_METARS_URL).call().ok().and_then(|response| response.into_string().ok()) {
-                            Some(metar_file) => {
+                        match fetch_if_modified(&format!("{}?id=all", metar_url), &mut metar_validators) {
+                            Ok(Some(metar_file)) => {
+                                let mut metar_map = HashMap::new();
                                 for line in metar_file.lines() {
                                     let icao = match line.split_whitespace().next() {
                                         Some(icao) => icao.to_owned(),
                                         None => continue,
                                     };
                                     if icao.len() < 4 { continue; }
-                                    let mut metar_map = metars.lock().unwrap();
                                     metar_map.insert(icao, line.to_owned());
                                     count += 1;
                                 }
+                                metars.store(Arc::new(metar_map));
+                                requested_stations.lock().unwrap().clear();
+                                let mut status = status.lock().unwrap();
+                                status.metars_last_updated = Some(unix_timestamp());
+                                status.metar_count = count;
+                                status.metars_last_error = None;
+                                println!("{} METARs fetched", count);
+                            },
+                            Ok(None) => {
+                                // Stations missing from the table may have been added to the service since they were asked for
+                                requested_stations.lock().unwrap().clear();
+                                let mut status = status.lock().unwrap();
+                                status.metars_last_updated = Some(unix_timestamp());
+                                status.metars_last_error = None;
+                                println!("METARs unchanged");
+                            },
+                            Err(error) => {
+                                println!("Unable to retrieve METARs from VATSIM");
+                                status.lock().unwrap().metars_last_error = Some(error);
                             },
-                            None => println!("Unable to retrieve METARs from VATSIM"),
                         }
-                        println!("{} METARs fetched", count);
                     },
 
-                    WorkerCommand::RefreshVatsimData => {
-                        let mut count = 0;
-                        let mut new_count = 0;
-                        let res = ureq::get(VATSIM_DATA_URL).call().unwrap().into_json::<Value>().unwrap();
-                        let pilots = res.get("pilots").unwrap();
-                        for value in pilots.as_array().unwrap().iter() {
-                            let new = match serde_json::from_value::<Details>(value.clone()) {
-                                Ok(details) => details,
-                                Err(_) => continue,
-                            };
-
-                            let mut details_map = vatsim_data.lock().unwrap();
-                            //let mut is_new = true;
-
-                            // If we already have the details for this pilot
-                            if let Some(existing) = details_map.get_mut(&new.callsign) {
-                                // Set 'dirty' to true
-                                existing.1 = true;
-                                if let (Some(new_fp), Some(existing_fp)) = (&new.flight_plan, &existing.0.flight_plan) {
-                                    if existing_fp.revision_id == new_fp.revision_id {
-                                        // Only if there is a new flight plan and an old flight plan and the revision IDs are the same do we set 'dirty' to false
-                                        // So basically we set dirty to true if:
-                                        // - There was no old flight plan but there is a new one
-                                        // - There was an old flight plan but there is no new one
-                                        // - There was an old flight plan and a new one and the new one does not have the same revision ID as the old one
-                                        existing.1 = false;
-                                        //is_new = false;
-                                    }
-                                }
-                                existing.0 = new;
-                                count += 1;
-                            } else {
-                                details_map.insert(new.callsign.clone(), (new, true));
-                                count += 1;
-                            }
-                            // if is_new { 
-                            //     new_count += 1;
-                            // }
+                    WorkerCommand::FetchMetar(icao) => {
+                        let Some(metar_url) = &sources.metar_url else {
+                            continue;
                         };
-                        println!("Details of {} aircraft fetched from VATSIM, of which {} were new", count, new_count);
+                        let metar = ureq::get(metar_url).query("id", &icao).call().ok().and_then(|response| response.into_string().ok());
+                        if let Some(metar) = metar.as_deref().and_then(|metar| metar.lines().find(|line| line.starts_with(&icao))) {
+                            metars.rcu(|current| {
+                                let mut metar_map = HashMap::clone(current);
+                                metar_map.insert(icao.clone(), metar.to_owned());
+                                metar_map
+                            });
+                        }
+                    },
+
+                    WorkerCommand::RefreshNetworkData => {
+                        let provider = &mut sources.provider;
+                        let url = sources.network_data_url.as_deref().unwrap_or(provider.data_url());
+                        let data = match fetch_if_modified(url, &mut network_data_validators).and_then(|body| body.map(|body| provider.parse(&body)).transpose()) {
+                            Ok(Some(Some(data))) => data,
+                            // The feed hasn't been updated since the last refresh, which still counts as a
+                            // successful one
+                            Ok(_) => {
+                                let mut status = status.lock().unwrap();
+                                status.network_data_last_updated = Some(unix_timestamp());
+                                status.network_data_last_error = None;
+                                continue;
+                            },
+                            Err(error) => {
+                                println!("Unable to retrieve data from {}", provider.name());
+                                status.lock().unwrap().network_data_last_error = Some(error);
+                                continue;
+                            },
+                        };
+                        let count = data.pilots.len();
+                        let (snapshot, events) = NetworkSnapshot::next(&network.load(), data);
+                        let new_count = events.iter().filter(|event| matches!(event, NetworkEvent::PilotAdded(_))).count();
+
+                        let mut subscribers = subscribers.lock().unwrap();
+                        network.store(Arc::new(snapshot));
+                        subscribers.retain(|subscriber| events.iter().all(|event| subscriber.send(event.clone()).is_ok()));
+                        drop(subscribers);
+
+                        let mut status = status.lock().unwrap();
+                        status.network_data_last_updated = Some(unix_timestamp());
+                        status.pilot_count = count;
+                        status.network_data_last_error = None;
+                        println!("Details of {} aircraft fetched from {}, of which {} were new", count, provider.name(), new_count);
                     },
                     WorkerCommand::Stop => break,
                 },
@@ -164,4 +474,198 @@ fn worker_thread(receiver: Receiver<WorkerCommand>, metars: Arc<Mutex<HashMap<St
             }
         }
     }).unwrap()
-}
\ No newline at end of file
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+
+    use super::*;
+    use crate::vatsim::VatsimProvider;
+
+    /// A full-size v3 feed, with as many pilots, controllers and prefiles as VATSIM has at a busy time.
+    const FULL_FIXTURE: &str = include_str!("../tests/fixtures/vatsim-data-full.json");
+
+    fn full_data() -> NetworkData {
+        VatsimProvider::default().parse(FULL_FIXTURE).unwrap().unwrap()
+    }
+
+    #[test]
+    fn snapshots_a_full_feed() {
+        let data = full_data();
+        let (pilots, prefiles) = (data.pilots.len(), data.prefiles.len());
+        let (snapshot, events) = NetworkSnapshot::next(&NetworkSnapshot::default(), data.clone());
+        assert_eq!(snapshot.online.len(), pilots);
+        assert_eq!(snapshot.pilots.len(), pilots + prefiles);
+        assert_eq!(events.len(), pilots + prefiles);
+        assert!(events.iter().all(|event| matches!(event, NetworkEvent::PilotAdded(_))));
+
+        let (_, events) = NetworkSnapshot::next(&snapshot, data);
+        assert_eq!(events, []);
+    }
+
+    /// Answers HTTP requests on a local port with `respond`, which is given each URL and `If-None-Match` header
+    /// and returns a status and body. Every `200` response carries the ETag `"v1"`. Gives the base URL and a
+    /// record of the URLs requested.
+    fn stand_in(respond: impl Fn(&str, Option<&str>) -> (u16, String) + Send + 'static) -> (String, Arc<Mutex<Vec<String>>>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let record = Arc::clone(&requests);
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let if_none_match = request.headers().iter().find(|header| header.field.equiv("If-None-Match")).map(|header| header.value.to_string());
+                let (status, body) = respond(request.url(), if_none_match.as_deref());
+                record.lock().unwrap().push(request.url().to_owned());
+                let mut response = tiny_http::Response::from_string(body).with_status_code(status);
+                if status == 200 {
+                    response.add_header(tiny_http::Header::from_bytes("ETag", "\"v1\"").unwrap());
+                }
+                request.respond(response).ok();
+            }
+        });
+        (base_url, requests)
+    }
+
+    /// Waits up to a few seconds for `condition` to hold.
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let started = Instant::now();
+        while !condition() {
+            if started.elapsed() > Duration::from_secs(5) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn sends_validators_and_honours_not_modified() {
+        let (base_url, requests) = stand_in(|_, if_none_match| match if_none_match {
+            Some("\"v1\"") => (304, String::new()),
+            _ => (200, String::from("EGLL 121820Z 25012KT 9999 SCT024 18/11 Q1013")),
+        });
+        let mut validators = CacheValidators::default();
+        let body = fetch_if_modified(&base_url, &mut validators).unwrap();
+        assert!(body.unwrap().starts_with("EGLL"));
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(fetch_if_modified(&base_url, &mut validators), Ok(None));
+
+        // Without the validators the document is sent again
+        assert!(fetch_if_modified(&base_url, &mut CacheValidators::default()).unwrap().is_some());
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn fetches_missing_stations_once_per_refresh() {
+        let (base_url, requests) = stand_in(|url, if_none_match| match (url, if_none_match) {
+            ("/metar.php?id=all", Some(_)) => (304, String::new()),
+            ("/metar.php?id=all", None) => (200, String::from("EGKK 121820Z 24010KT CAVOK 19/10 Q1014\n")),
+            ("/metar.php?id=EGLL", _) => (200, String::from("EGLL 121820Z 25012KT 9999 SCT024 18/11 Q1013\n")),
+            _ => (200, String::new()),
+        });
+        let requests_for = |url: &str| requests.lock().unwrap().iter().filter(|request| request.as_str() == url).count();
+        let metar_config = MetarConfig { url: Some(format!("{}/metar.php", base_url)), ..Default::default() };
+        let mut worker = Worker::start(Some(&metar_config), &NetworkConfig::default());
+        let handle = worker.handle();
+
+        // Asking repeatedly while the fetch is under way sends a single request
+        for _ in 0..5 {
+            assert_eq!(worker.get_metar("egll"), None);
+        }
+        assert!(wait_for(|| handle.metars().contains_key("EGLL")));
+        assert!(worker.get_metar("EGLL").unwrap().starts_with("EGLL 121820Z"));
+        assert_eq!(requests_for("/metar.php?id=EGLL"), 1);
+
+        // A station the service doesn't know isn't asked for again until the table is refreshed
+        for _ in 0..5 {
+            assert_eq!(worker.get_metar("ZZZZ"), None);
+        }
+        assert!(wait_for(|| requests_for("/metar.php?id=ZZZZ") == 1));
+        worker.get_metar("ZZZZ");
+        worker.refresh_metars();
+        assert!(wait_for(|| handle.metars().contains_key("EGKK")));
+        assert!(wait_for(|| { worker.get_metar("ZZZZ"); requests_for("/metar.php?id=ZZZZ") == 2 }));
+
+        // Nor after a refresh which finds the table unchanged
+        worker.refresh_metars();
+        assert!(wait_for(|| { worker.get_metar("ZZZZ"); requests_for("/metar.php?id=ZZZZ") == 3 }));
+        assert_eq!(requests_for("/metar.php?id=all"), 2);
+    }
+
+    /// Runs `refresh` repeatedly while reader threads look every pilot up with `read`, and returns how long
+    /// the refreshes took, how many lookups were made meanwhile, and the slowest lookup.
+    fn race(refresh: impl Fn(), read: impl Fn(&str) + Sync, callsigns: &[String]) -> (Duration, u64, Duration) {
+        const READERS: usize = 2;
+        const REFRESHES: usize = 20;
+        let done = AtomicBool::new(false);
+        let reads = AtomicU64::new(0);
+        let slowest = AtomicU64::new(0);
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..READERS {
+                scope.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        for callsign in callsigns {
+                            let started = Instant::now();
+                            read(callsign);
+                            slowest.fetch_max(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                        }
+                        reads.fetch_add(callsigns.len() as u64, Ordering::Relaxed);
+                    }
+                });
+            }
+            for _ in 0..REFRESHES {
+                refresh();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        (started.elapsed(), reads.into_inner(), Duration::from_nanos(slowest.into_inner()))
+    }
+
+    /// Compares publishing snapshots with the locked map the worker used to refill one pilot at a time.
+    /// Run with `cargo test --release -- --ignored --nocapture benchmark`, on a machine with a core each for
+    /// the refreshes and the readers, or the readers only measure the scheduler.
+    #[test]
+    #[ignore]
+    fn benchmark_snapshot_publishing() {
+        let data = full_data();
+        let callsigns: Vec<String> = data.pilots.iter().map(|details| details.callsign.clone()).collect();
+
+        let locked = Mutex::new(HashMap::<String, Details>::new());
+        let (locked_time, locked_reads, locked_slowest) = race(
+            || {
+                let data = data.clone();
+                let mut callsigns = HashSet::new();
+                for details in data.pilots.into_iter().chain(data.prefiles) {
+                    callsigns.insert(details.callsign.clone());
+                    locked.lock().unwrap().insert(details.callsign.clone(), details);
+                }
+                locked.lock().unwrap().retain(|callsign, _| callsigns.contains(callsign));
+            },
+            |callsign| { black_box(locked.lock().unwrap().get(callsign).cloned()); },
+            &callsigns,
+        );
+
+        let published = ArcSwap::from_pointee(NetworkSnapshot::default());
+        let (snapshot_time, snapshot_reads, snapshot_slowest) = race(
+            || {
+                let (snapshot, events) = NetworkSnapshot::next(&published.load(), data.clone());
+                published.store(Arc::new(snapshot));
+                black_box(events);
+            },
+            |callsign| { black_box(published.load().pilots.get(callsign).cloned()); },
+            &callsigns,
+        );
+
+        let per_second = |reads: u64, time: Duration| reads as f64 / time.as_secs_f64();
+        println!("Locked map: refreshes took {:?}, {:.0} lookups/s, slowest lookup {:?}", locked_time, per_second(locked_reads, locked_time), locked_slowest);
+        println!("Snapshots:  refreshes took {:?}, {:.0} lookups/s, slowest lookup {:?}", snapshot_time, per_second(snapshot_reads, snapshot_time), snapshot_slowest);
+    }
+}
+